@@ -0,0 +1,39 @@
+#!/usr/bin/env python3
+#
+# Minimal secrets manager mock for cert_mode "aws", answers GetSecretValue with the
+# content of <dir>/<last part of the SecretId>
+#
+# usage: scripts/mock-secrets-manager.py <dir> [port]
+# then:  AWS_ENDPOINT_URL=http://localhost:4566 rust-burn-queuemetrics --config app-config.json serve
+
+import json
+import os
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+CERTS_DIR = sys.argv[1] if len(sys.argv) > 1 else "./certs"
+PORT = int(sys.argv[2]) if len(sys.argv) > 2 else 4566
+
+
+class Handler(BaseHTTPRequestHandler):
+    def do_POST(self):
+        if self.headers.get("X-Amz-Target") != "secretsmanager.GetSecretValue":
+            return self.reply(400, {"__type": "InvalidRequestException"})
+        length = int(self.headers.get("Content-Length", 0))
+        secret_id = json.loads(self.rfile.read(length)).get("SecretId", "")
+        path = os.path.join(CERTS_DIR, secret_id.split("/")[-1])
+        if not os.path.isfile(path):
+            return self.reply(400, {"__type": "ResourceNotFoundException", "message": secret_id})
+        with open(path) as f:
+            self.reply(200, {"Name": secret_id, "SecretString": f.read()})
+
+    def reply(self, code, body):
+        data = json.dumps(body).encode()
+        self.send_response(code)
+        self.send_header("Content-Type", "application/x-amz-json-1.1")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+
+HTTPServer(("127.0.0.1", PORT), Handler).serve_forever()
@@ -0,0 +1,55 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// The predicted class of an inference response, added to the response extensions for the access log
+#[derive(Clone, Debug)]
+pub struct PredictedClass {
+    pub class: String,
+    pub confidence: f64,
+}
+
+/// One access log line
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry<'a> {
+    pub timestamp: String,
+    pub request_id: &'a str,
+    pub remote_addr: String,
+    /// api key name, token subject or client certificate subject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub version: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub predicted_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Current time for the timestamp field
+pub fn now() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+/// Writes the entry as a json line to stdout
+pub fn write(entry: &AccessLogEntry) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Ok(line) = serde_json::to_string(entry) {
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::certhandler::error;
+use crate::serverconfig::{Auth, JwtAuth};
+use crate::serviceerror::ServiceError;
+
+use custom_logger as log;
+use http::HeaderMap;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use ring::digest;
+use serde_json::Value;
+use std::io;
+use std::sync::OnceLock;
+
+pub const SCOPE_INFER: &str = "infer";
+pub const SCOPE_ADMIN: &str = "admin";
+const SCOPES: &[&str] = &[SCOPE_INFER, SCOPE_ADMIN];
+
+/// The authenticated caller, added to the request extensions
+#[derive(Clone, Debug)]
+pub struct Principal {
+    /// api key name or token subject
+    pub name: String,
+    pub scopes: Vec<String>,
+}
+
+struct KeyEntry {
+    name: String,
+    sha256: Vec<u8>,
+    scopes: Vec<String>,
+}
+
+struct JwtVerifier {
+    jwks: JwkSet,
+    config: JwtAuth,
+}
+
+struct Authenticator {
+    api_keys: Vec<KeyEntry>,
+    jwt: Option<JwtVerifier>,
+    protect_metrics: bool,
+}
+
+static AUTHENTICATOR: OnceLock<Authenticator> = OnceLock::new();
+
+/// Turns authentication on, fails on malformed key hashes, unknown scopes or an unreadable jwks file
+pub fn init(config: Auth) -> io::Result<()> {
+    let authenticator = Authenticator::new(config)?;
+    log::info!(
+        "auth : {} api keys : jwt {} : metrics {}",
+        authenticator.api_keys.len(),
+        match &authenticator.jwt {
+            Some(jwt) => format!("{} keys from {}", jwt.jwks.keys.len(), jwt.config.jwks_file),
+            None => "disabled".to_string(),
+        },
+        if authenticator.protect_metrics {
+            "admin"
+        } else {
+            "open"
+        }
+    );
+    let _ = AUTHENTICATOR.set(authenticator);
+    Ok(())
+}
+
+/// Checks the api key (X-Api-Key or Authorization: Bearer) or bearer jwt has the scope the route
+/// needs, None when auth is not configured or the route is open
+pub fn authenticate(headers: &HeaderMap, path: &str) -> Result<Option<Principal>, ServiceError> {
+    let Some(authenticator) = AUTHENTICATOR.get() else {
+        return Ok(None);
+    };
+    let Some(scope) = authenticator.required_scope(path) else {
+        return Ok(None);
+    };
+    authenticator.authenticate(headers, scope).map(Some)
+}
+
+fn credential(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+    let authorization = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())?;
+    match authorization.split_once(' ') {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+impl Authenticator {
+    fn new(config: Auth) -> io::Result<Authenticator> {
+        let mut api_keys = Vec::new();
+        for key in config.api_keys {
+            let sha256 = decode_hex(&key.sha256)
+                .filter(|hash| hash.len() == digest::SHA256_OUTPUT_LEN)
+                .ok_or_else(|| {
+                    error(format!(
+                        "api key {}: sha256 must be 64 hex characters",
+                        key.name
+                    ))
+                })?;
+            check_scopes(&key.name, &key.scopes)?;
+            api_keys.push(KeyEntry {
+                name: key.name,
+                sha256,
+                scopes: key.scopes,
+            });
+        }
+        let jwt = match config.jwt {
+            Some(config) => {
+                let data = std::fs::read(&config.jwks_file).map_err(|e| {
+                    error(format!("failed to read jwks {}: {}", config.jwks_file, e))
+                })?;
+                let jwks: JwkSet = serde_json::from_slice(&data)
+                    .map_err(|e| error(format!("invalid jwks {}: {}", config.jwks_file, e)))?;
+                if jwks.keys.is_empty() {
+                    return Err(error(format!("jwks {} has no keys", config.jwks_file)));
+                }
+                Some(JwtVerifier { jwks, config })
+            }
+            None => None,
+        };
+        if api_keys.is_empty() && jwt.is_none() {
+            return Err(error("auth needs api_keys or jwt".to_string()));
+        }
+        Ok(Authenticator {
+            api_keys,
+            jwt,
+            protect_metrics: config.protect_metrics,
+        })
+    }
+
+    // scope needed for the route, probes, unknown routes and (unless protected) /metrics need none
+    fn required_scope(&self, path: &str) -> Option<&'static str> {
+        match path {
+            "/inference" | "/feedback" => Some(SCOPE_INFER),
+            "/model" => Some(SCOPE_ADMIN),
+            "/metrics" if self.protect_metrics => Some(SCOPE_ADMIN),
+            _ => None,
+        }
+    }
+
+    // the credential must verify and carry the scope
+    fn authenticate(&self, headers: &HeaderMap, scope: &str) -> Result<Principal, ServiceError> {
+        let credential = credential(headers).ok_or(ServiceError::Unauthorized(
+            "an api key or bearer token is required".to_string(),
+        ))?;
+        let principal = self.verify(credential)?;
+        if !principal.scopes.iter().any(|s| s == scope) {
+            return Err(ServiceError::Forbidden(format!(
+                "{} doesn't have the {} scope",
+                principal.name, scope
+            )));
+        }
+        Ok(principal)
+    }
+
+    // api keys are compared by hash, anything shaped like a jwt is then checked against the jwks
+    fn verify(&self, credential: &str) -> Result<Principal, ServiceError> {
+        let hash = digest::digest(&digest::SHA256, credential.as_bytes());
+        if let Some(key) = self
+            .api_keys
+            .iter()
+            .find(|key| hash_matches(&key.sha256, hash.as_ref()))
+        {
+            return Ok(Principal {
+                name: key.name.clone(),
+                scopes: key.scopes.clone(),
+            });
+        }
+        match &self.jwt {
+            Some(jwt) if credential.split('.').count() == 3 => jwt.verify(credential),
+            _ => Err(ServiceError::Unauthorized("invalid api key".to_string())),
+        }
+    }
+}
+
+impl JwtVerifier {
+    fn verify(&self, token: &str) -> Result<Principal, ServiceError> {
+        let invalid =
+            |reason: String| ServiceError::Unauthorized(format!("invalid token: {}", reason));
+        let header = jsonwebtoken::decode_header(token).map_err(|e| invalid(e.to_string()))?;
+        // the keys are public, a shared secret algorithm would let anyone sign with them
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(invalid(format!("{:?} is not accepted", header.alg)));
+        }
+        let jwk = self
+            .find_key(header.kid.as_deref())
+            .ok_or_else(|| invalid("no matching key in the jwks".to_string()))?;
+        if let Some(alg) = &jwk.common.key_algorithm {
+            if format!("{:?}", alg) != format!("{:?}", header.alg) {
+                return Err(invalid(format!(
+                    "key is for {:?}, token uses {:?}",
+                    alg, header.alg
+                )));
+            }
+        }
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| invalid(e.to_string()))?;
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<Value>(token, &key, &validation)
+            .map_err(|e| invalid(e.to_string()))?
+            .claims;
+        let scopes = match claims.get(&self.config.scopes_claim) {
+            Some(Value::String(scopes)) => scopes.split_whitespace().map(String::from).collect(),
+            Some(Value::Array(scopes)) => scopes
+                .iter()
+                .filter_map(Value::as_str)
+                .map(String::from)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(Principal {
+            name: claims
+                .get("sub")
+                .and_then(Value::as_str)
+                .unwrap_or("jwt")
+                .to_string(),
+            scopes,
+        })
+    }
+
+    // tokens without a kid are accepted when the jwks has a single key
+    fn find_key(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self.jwks.find(kid),
+            None if self.jwks.keys.len() == 1 => self.jwks.keys.first(),
+            None => None,
+        }
+    }
+}
+
+// constant time so the compare doesn't leak how much of a hash matched, ring keeps the function
+// deprecated as internal but it's the constant time compare our dependencies have
+#[allow(deprecated)]
+fn hash_matches(expected: &[u8], hash: &[u8]) -> bool {
+    ring::constant_time::verify_slices_are_equal(expected, hash).is_ok()
+}
+
+fn check_scopes(name: &str, scopes: &[String]) -> io::Result<()> {
+    match scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        Some(scope) => Err(error(format!(
+            "api key {}: unknown scope {} (available: {})",
+            name,
+            scope,
+            SCOPES.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn sha256_hex(key: &str) -> String {
+        digest::digest(&digest::SHA256, key.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn config(value: Value) -> Auth {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn api_keys() -> Authenticator {
+        Authenticator::new(config(json!({
+            "api_keys": [
+                { "name": "scheduler", "sha256": sha256_hex("s3cret") },
+                { "name": "ops", "sha256": sha256_hex("0ps").to_uppercase(), "scopes": ["infer", "admin"] }
+            ]
+        })))
+        .unwrap()
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+        headers
+    }
+
+    // an ES256 key pair, the jwks file with its public key and a token signer
+    struct Issuer {
+        pkcs8: Vec<u8>,
+        jwks_file: String,
+    }
+
+    impl Issuer {
+        fn new(name: &str) -> Issuer {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .unwrap()
+                .as_ref()
+                .to_vec();
+            let pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng).unwrap();
+            // uncompressed point, 0x04 then x and y
+            let point = pair.public_key().as_ref();
+            let jwks = json!({ "keys": [{
+                "kty": "EC", "crv": "P-256", "kid": "k1", "alg": "ES256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]), "y": URL_SAFE_NO_PAD.encode(&point[33..65])
+            }]});
+            let jwks_file = std::env::temp_dir()
+                .join(format!("queuemetrics-{}-{}.json", name, std::process::id()))
+                .to_string_lossy()
+                .to_string();
+            std::fs::write(&jwks_file, jwks.to_string()).unwrap();
+            Issuer { pkcs8, jwks_file }
+        }
+
+        fn token(&self, claims: Value) -> String {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some("k1".to_string());
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_ec_der(&self.pkcs8)).unwrap()
+        }
+
+        fn authenticator(&self) -> Authenticator {
+            Authenticator::new(config(json!({
+                "jwt": { "jwks_file": self.jwks_file, "issuer": "qm" }
+            })))
+            .unwrap()
+        }
+    }
+
+    impl Drop for Issuer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.jwks_file);
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn decodes_hex_hashes() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex(" AbCd "), Some(vec![171, 205]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_api_key_config() {
+        for (value, message) in [
+            (
+                json!({ "api_keys": [{ "name": "short", "sha256": "abcd" }] }),
+                "api key short: sha256 must be 64 hex characters",
+            ),
+            (
+                json!({ "api_keys": [{ "name": "k", "sha256": sha256_hex("k"), "scopes": ["root"] }] }),
+                "api key k: unknown scope root (available: infer, admin)",
+            ),
+            (json!({}), "auth needs api_keys or jwt"),
+        ] {
+            let err = Authenticator::new(config(value)).err().unwrap();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn matches_api_keys_by_hash() {
+        let authenticator = api_keys();
+        let principal = authenticator
+            .authenticate(&headers("x-api-key", "s3cret"), SCOPE_INFER)
+            .unwrap();
+        assert_eq!(principal.name, "scheduler");
+        assert_eq!(principal.scopes, vec!["infer"]);
+        // the same key as a bearer token, the upper case hash is accepted too
+        let principal = authenticator
+            .authenticate(&headers("authorization", "Bearer 0ps"), SCOPE_ADMIN)
+            .unwrap();
+        assert_eq!(principal.name, "ops");
+        assert!(matches!(
+            authenticator.authenticate(&headers("x-api-key", "wrong"), SCOPE_INFER),
+            Err(ServiceError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            authenticator.authenticate(&HeaderMap::new(), SCOPE_INFER),
+            Err(ServiceError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            authenticator.authenticate(&headers("authorization", "Basic czNjcmV0"), SCOPE_INFER),
+            Err(ServiceError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn checks_the_route_scope() {
+        let authenticator = api_keys();
+        assert_eq!(
+            authenticator.required_scope("/inference"),
+            Some(SCOPE_INFER)
+        );
+        assert_eq!(authenticator.required_scope("/feedback"), Some(SCOPE_INFER));
+        assert_eq!(authenticator.required_scope("/model"), Some(SCOPE_ADMIN));
+        assert_eq!(authenticator.required_scope("/metrics"), None);
+        assert_eq!(authenticator.required_scope("/healthz"), None);
+        let protected = Authenticator::new(config(json!({
+            "api_keys": [{ "name": "ops", "sha256": sha256_hex("0ps"), "scopes": ["admin"] }],
+            "protect_metrics": true
+        })))
+        .unwrap();
+        assert_eq!(protected.required_scope("/metrics"), Some(SCOPE_ADMIN));
+        assert_eq!(protected.required_scope("/readyz"), None);
+        match authenticator.authenticate(&headers("x-api-key", "s3cret"), SCOPE_ADMIN) {
+            Err(ServiceError::Forbidden(message)) => {
+                assert_eq!(message, "scheduler doesn't have the admin scope")
+            }
+            other => panic!("expected a 403, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifies_jwt_signature_claims_and_scopes() {
+        let issuer = Issuer::new("jwks");
+        let authenticator = issuer.authenticator();
+        let token = issuer.token(
+            json!({ "sub": "ops", "iss": "qm", "exp": now() + 600, "scope": "infer admin" }),
+        );
+        let principal = authenticator
+            .authenticate(
+                &headers("authorization", &format!("Bearer {token}")),
+                SCOPE_ADMIN,
+            )
+            .unwrap();
+        assert_eq!(principal.name, "ops");
+        assert_eq!(principal.scopes, vec!["infer", "admin"]);
+
+        let token = issuer
+            .token(json!({ "sub": "sched", "iss": "qm", "exp": now() + 600, "scope": ["infer"] }));
+        let bearer = headers("authorization", &format!("Bearer {token}"));
+        assert!(authenticator.authenticate(&bearer, SCOPE_INFER).is_ok());
+        assert!(matches!(
+            authenticator.authenticate(&bearer, SCOPE_ADMIN),
+            Err(ServiceError::Forbidden(_))
+        ));
+
+        for claims in [
+            json!({ "sub": "x", "iss": "other", "exp": now() + 600, "scope": "admin" }),
+            json!({ "sub": "x", "iss": "qm", "exp": now() - 600, "scope": "admin" }),
+        ] {
+            let token = issuer.token(claims);
+            assert!(matches!(
+                authenticator.authenticate(&headers("x-api-key", &token), SCOPE_ADMIN),
+                Err(ServiceError::Unauthorized(_))
+            ));
+        }
+        // signed by another key with the same kid
+        let token = Issuer::new("other-jwks")
+            .token(json!({ "sub": "x", "iss": "qm", "exp": now() + 600, "scope": "admin" }));
+        assert!(matches!(
+            authenticator.authenticate(&headers("x-api-key", &token), SCOPE_ADMIN),
+            Err(ServiceError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_shared_secret_tokens() {
+        let issuer = Issuer::new("hs-jwks");
+        let authenticator = issuer.authenticator();
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &json!({ "sub": "x", "iss": "qm", "exp": now() + 600, "scope": "admin" }),
+            &EncodingKey::from_secret(b"public"),
+        )
+        .unwrap();
+        match authenticator.authenticate(&headers("x-api-key", &token), SCOPE_ADMIN) {
+            Err(ServiceError::Unauthorized(message)) => {
+                assert_eq!(message, "invalid token: HS256 is not accepted")
+            }
+            other => panic!("expected a 401, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use burn::data::dataloader::batcher::Batcher;
+
+use crate::{
+    dataset::QueueMetrics,
+    metrics, modelstore,
+    serverconfig::{Batching, InferencePool},
+    serviceerror::ServiceError,
+};
+
+use custom_logger as log;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+/// Model output for one row
+#[derive(Clone, Debug)]
+pub struct Prediction {
+    /// target column of the row as sent by the client
+    pub expected: usize,
+    pub logits: Vec<f32>,
+}
+
+// a queued row and where to send its prediction
+struct Job {
+    item: QueueMetrics,
+    queued: Instant,
+    reply: oneshot::Sender<Result<Prediction, ServiceError>>,
+}
+
+static QUEUE: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+/// Starts the batching task, rows queued while the first row of a batch waits (up to max_wait_ms)
+/// share one forward pass of up to max_batch_size rows. Batches run on blocking threads, at most
+/// pool.workers at a time, so forward passes don't hold up the runtime threads serving connections
+pub fn start(config: Batching, pool: InferencePool) {
+    let (tx, rx) = mpsc::channel(pool.queue_size);
+    if QUEUE.set(tx).is_err() {
+        log::warn!("inference queue is already started");
+        return;
+    }
+    metrics::set_batch_config(config.max_batch_size, config.max_wait_ms);
+    log::info!(
+        "inference batching : max batch size {} : max wait {}ms : workers {} : queue size {}",
+        config.max_batch_size,
+        config.max_wait_ms,
+        pool.workers,
+        pool.queue_size
+    );
+    tokio::spawn(run(rx, config, Arc::new(Semaphore::new(pool.workers))));
+}
+
+/// Queues the row and waits for the batch it ends up in, 503 when the queue is full
+pub async fn predict(item: QueueMetrics) -> Result<Prediction, ServiceError> {
+    let queue = QUEUE.get().ok_or(ServiceError::Unavailable(
+        "inference queue is not started".to_string(),
+    ))?;
+    let (reply, result) = oneshot::channel();
+    let job = Job {
+        item,
+        queued: Instant::now(),
+        reply,
+    };
+    let sent = queue.try_send(job);
+    // rows waiting for the batching task, the receiving side updates it as it takes them
+    metrics::set_queue_depth(queue.max_capacity() - queue.capacity());
+    match sent {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            metrics::inc_rejected("queue_full");
+            return Err(ServiceError::Unavailable(
+                "inference queue is full".to_string(),
+            ));
+        }
+        Err(TrySendError::Closed(_)) => {
+            return Err(ServiceError::Unavailable(
+                "inference queue is closed".to_string(),
+            ))
+        }
+    }
+    result
+        .await
+        .map_err(|_| ServiceError::Internal("inference batch dropped the request".to_string()))?
+}
+
+// a batch is only gathered once a worker is free, while all workers are busy rows pile up in the
+// queue and make the next batches larger
+async fn run(mut rx: mpsc::Receiver<Job>, config: Batching, workers: Arc<Semaphore>) {
+    let max_wait = Duration::from_millis(config.max_wait_ms);
+    loop {
+        let Ok(permit) = workers.clone().acquire_owned().await else {
+            return;
+        };
+        let Some(first) = rx.recv().await else {
+            return;
+        };
+        metrics::set_queue_depth(rx.len());
+        let deadline = tokio::time::Instant::now() + max_wait;
+        let mut jobs = vec![first];
+        // rows already queued are taken even when the deadline has passed
+        while jobs.len() < config.max_batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(job)) => {
+                    jobs.push(job);
+                    metrics::set_queue_depth(rx.len());
+                }
+                Ok(None) | Err(_) => break,
+            }
+        }
+        tokio::task::spawn_blocking(move || {
+            run_batch(jobs);
+            drop(permit);
+        });
+    }
+}
+
+// one forward pass for the jobs, every caller gets its own row or the batch error
+fn run_batch(jobs: Vec<Job>) {
+    metrics::observe_batch_size(jobs.len());
+    let mut items = Vec::with_capacity(jobs.len());
+    let mut replies = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        metrics::observe_stage("queue", job.queued.elapsed().as_secs_f64());
+        items.push(job.item);
+        replies.push(job.reply);
+    }
+    match forward(items) {
+        Ok(predictions) => {
+            for (reply, prediction) in replies.into_iter().zip(predictions) {
+                let _ = reply.send(Ok(prediction));
+            }
+        }
+        Err(err) => {
+            for reply in replies {
+                let _ = reply.send(Err(err.clone()));
+            }
+        }
+    }
+}
+
+fn forward(items: Vec<QueueMetrics>) -> Result<Vec<Prediction>, ServiceError> {
+    let loaded = modelstore::get()?;
+    let rows = items.len();
+
+    let start = Instant::now();
+    let batch = loaded.batcher.batch(items, &loaded.device);
+    metrics::observe_stage("batch", start.elapsed().as_secs_f64());
+
+    // reading the output back waits for the device, so it's part of the forward time
+    let start = Instant::now();
+    let output = loaded.model()?.forward(batch.inputs, batch.categories);
+    let expected = batch.targets.into_data().iter::<f32>().collect::<Vec<_>>();
+    let logits = output
+        .into_data()
+        .into_vec::<f32>()
+        .map_err(|e| ServiceError::Internal(format!("{:?}", e)))?;
+    metrics::observe_stage("forward", start.elapsed().as_secs_f64());
+
+    if rows == 0 || logits.is_empty() || logits.len() % rows != 0 || expected.len() != rows {
+        return Err(ServiceError::Internal(format!(
+            "model returned {} outputs for {} rows",
+            logits.len(),
+            rows
+        )));
+    }
+    Ok(logits
+        .chunks(logits.len() / rows)
+        .zip(expected)
+        .map(|(logits, expected)| Prediction {
+            expected: expected as usize,
+            logits: logits.to_vec(),
+        })
+        .collect())
+}
@@ -0,0 +1,68 @@
+use crate::dataset::{QueueMetrics, QueueMetricsBatcher, QueueMetricsDataset};
+use crate::schema::FeatureSchema;
+use burn::{
+    data::{dataloader::batcher::Batcher, dataset::Dataset},
+    tensor::backend::Backend,
+};
+use std::time::{Duration, Instant};
+
+/// Compares the per row batching with the flat buffer batching, rows are taken from the
+/// test split and repeated to fill the batch
+pub fn run<B: Backend>(
+    schema: &FeatureSchema,
+    batch_size: usize,
+    iterations: usize,
+    device: B::Device,
+) {
+    let dataset = QueueMetricsDataset::test(schema);
+    let rows: Vec<QueueMetrics> = dataset.iter().collect();
+    if rows.is_empty() || batch_size == 0 || iterations == 0 {
+        println!("nothing to benchmark (test split is empty or batch size/iterations are 0)");
+        return;
+    }
+    let items: Vec<QueueMetrics> = rows.iter().cycle().take(batch_size).cloned().collect();
+    let batcher = QueueMetricsBatcher::<B>::new(device.clone(), schema);
+
+    // warm up, the first upload includes device and kernel initialization
+    let _ = batcher.batch(items.clone(), &device);
+    let _ = batcher.batch_per_row(items.clone(), &device);
+    B::sync(&device);
+
+    let per_row = measure::<B>(iterations, &device, || {
+        let _ = batcher.batch_per_row(items.clone(), &device);
+    });
+    let flat = measure::<B>(iterations, &device, || {
+        let _ = batcher.batch(items.clone(), &device);
+    });
+
+    let throughput = |elapsed: Duration| (batch_size * iterations) as f64 / elapsed.as_secs_f64();
+    println!(
+        "batch size {} : iterations {} : features {}",
+        batch_size,
+        iterations,
+        schema.input_size()
+    );
+    println!(
+        "per row batcher : {:>10.3} ms/batch : {:>12.0} rows/s",
+        per_row.as_secs_f64() * 1000.0 / iterations as f64,
+        throughput(per_row)
+    );
+    println!(
+        "flat batcher    : {:>10.3} ms/batch : {:>12.0} rows/s",
+        flat.as_secs_f64() * 1000.0 / iterations as f64,
+        throughput(flat)
+    );
+    println!(
+        "speedup         : {:>10.2}x",
+        per_row.as_secs_f64() / flat.as_secs_f64()
+    );
+}
+
+fn measure<B: Backend>(iterations: usize, device: &B::Device, f: impl Fn()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    B::sync(device);
+    start.elapsed()
+}
@@ -0,0 +1,173 @@
+use crate::certhandler::error;
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use time::{Duration, OffsetDateTime};
+
+const CA_VALIDITY_DAYS: i64 = 1024;
+const SERVER_VALIDITY_DAYS: i64 = 365;
+
+/// Pem encoded CA and server pair
+pub struct GeneratedCerts {
+    pub ca_cert: String,
+    pub ca_key: String,
+    pub cert: String,
+    pub key: String,
+}
+
+/// Creates a CA and a server certificate signed by it, valid for the hostname and ips
+pub fn generate(hostname: &str, ips: &[String]) -> io::Result<GeneratedCerts> {
+    // rcgen would take anything that isn't an ip as a dns name
+    if let Some(ip) = ips.iter().find(|ip| ip.parse::<IpAddr>().is_err()) {
+        return Err(error(format!("--ip {} is not an ip address", ip)));
+    }
+    let ca_key = KeyPair::generate().map_err(rcgen_error)?;
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).map_err(rcgen_error)?;
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, format!("{} CA", hostname));
+    ca_params
+        .distinguished_name
+        .push(DnType::OrganizationName, env!("CARGO_PKG_NAME"));
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    set_validity(&mut ca_params, CA_VALIDITY_DAYS);
+    let ca_cert = ca_params.self_signed(&ca_key).map_err(rcgen_error)?;
+
+    let key = KeyPair::generate().map_err(rcgen_error)?;
+    let params = server_params(hostname, ips)?;
+    let cert = params
+        .signed_by(&key, &ca_cert, &ca_key)
+        .map_err(rcgen_error)?;
+
+    Ok(GeneratedCerts {
+        ca_cert: ca_cert.pem(),
+        ca_key: ca_key.serialize_pem(),
+        cert: cert.pem(),
+        key: key.serialize_pem(),
+    })
+}
+
+/// Self signed server pair for localhost, used by cert_mode self-signed when there are no files
+pub fn self_signed() -> io::Result<(String, String)> {
+    let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+    let mut alt_names = vec!["127.0.0.1".to_string(), "::1".to_string()];
+    if hostname != "localhost" {
+        alt_names.push("localhost".to_string());
+    }
+    let params = server_params(&hostname, &alt_names)?;
+    let key = KeyPair::generate().map_err(rcgen_error)?;
+    let cert = params.self_signed(&key).map_err(rcgen_error)?;
+    Ok((cert.pem(), key.serialize_pem()))
+}
+
+/// Writes ca.cert, ca.key, ssl.cert and ssl.key to dir, existing files are kept unless force is set
+pub fn write(dir: &str, certs: &GeneratedCerts, force: bool) -> io::Result<Vec<String>> {
+    fs::create_dir_all(dir).map_err(|e| error(format!("failed to create {}: {}", dir, e)))?;
+    let files = [
+        ("ca.cert", &certs.ca_cert),
+        ("ca.key", &certs.ca_key),
+        ("ssl.cert", &certs.cert),
+        ("ssl.key", &certs.key),
+    ];
+    let paths: Vec<String> = files
+        .iter()
+        .map(|(name, _)| Path::new(dir).join(name).display().to_string())
+        .collect();
+    if !force {
+        if let Some(path) = paths.iter().find(|p| Path::new(p).exists()) {
+            return Err(error(format!(
+                "{} already exists (use --force to overwrite)",
+                path
+            )));
+        }
+    }
+    for ((name, pem), path) in files.iter().zip(paths.iter()) {
+        fs::write(path, pem).map_err(|e| error(format!("failed to write {}: {}", path, e)))?;
+        // private keys are only readable by the owner
+        if name.ends_with(".key") {
+            set_owner_only(path)?;
+        }
+    }
+    Ok(paths)
+}
+
+// the hostname and the alt names (dns names or ips) end up in the san extension
+fn server_params(hostname: &str, alt_names: &[String]) -> io::Result<CertificateParams> {
+    let mut names = vec![hostname.to_string()];
+    names.extend(alt_names.iter().cloned());
+    let mut params = CertificateParams::new(names).map_err(rcgen_error)?;
+    params.distinguished_name.push(DnType::CommonName, hostname);
+    params
+        .distinguished_name
+        .push(DnType::OrganizationName, env!("CARGO_PKG_NAME"));
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    set_validity(&mut params, SERVER_VALIDITY_DAYS);
+    Ok(params)
+}
+
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::hours(1);
+    params.not_after = now + Duration::days(days);
+}
+
+#[cfg(unix)]
+fn set_owner_only(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| error(format!("failed to set permissions on {}: {}", path, e)))
+}
+
+#[cfg(not(unix))]
+fn set_owner_only(_path: &str) -> io::Result<()> {
+    Ok(())
+}
+
+fn rcgen_error(err: rcgen::Error) -> io::Error {
+    error(format!("certificate generation failed: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::extensions::GeneralName;
+
+    #[test]
+    fn server_certificate_has_ip_sans() {
+        let certs = generate("queue.local", &["10.0.0.1".to_string(), "::1".to_string()]).unwrap();
+        let (_, pem) = x509_parser::pem::parse_x509_pem(certs.cert.as_bytes()).unwrap();
+        let cert = pem.parse_x509().unwrap();
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        let ips: Vec<&[u8]> = san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::IPAddress(ip) => Some(*ip),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ips.len(), 2);
+        assert_eq!(ips[0], &[10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn rejects_an_ip_that_is_not_an_address() {
+        let err = generate("queue.local", &["10.0.0".to_string()])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "--ip 10.0.0 is not an ip address");
+    }
+}
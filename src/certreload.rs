@@ -0,0 +1,144 @@
+use crate::certhandler::{
+    error, validate_chain, CertificateInterface, ChainInfo, ImplCertificateInterface,
+};
+
+use custom_logger as log;
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+
+// an unchanged certificate that is about to expire is reported again after this long
+const EXPIRY_WARNING_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Serves the current certificate pair, the pair is swapped when the source changes
+/// (checked every interval, or on SIGHUP) and the new pair is valid
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+    // warn when the certificate expires within this many days
+    expiry_warning_days: i64,
+    // last expiry warning, the poll doesn't repeat it for the same certificate every interval
+    expiry_warned: Mutex<Option<Instant>>,
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().ok().map(|current| current.clone())
+    }
+}
+
+impl ReloadingCertResolver {
+    /// Loads and validates the initial pair, the server doesn't start without one
+    pub async fn new(
+        certs: &ImplCertificateInterface,
+        expiry_warning_days: i64,
+    ) -> io::Result<ReloadingCertResolver> {
+        let (certified_key, info) = load(certs).await?;
+        let resolver = ReloadingCertResolver {
+            current: RwLock::new(Arc::new(certified_key)),
+            expiry_warning_days,
+            expiry_warned: Mutex::new(None),
+        };
+        resolver.check_expiry(&info, true);
+        Ok(resolver)
+    }
+
+    // swaps the pair if the source changed, an invalid pair keeps the current one
+    async fn reload(&self, certs: &ImplCertificateInterface) {
+        let (certified_key, info) = match load(certs).await {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                log::error!(
+                    "certificate reload failed, keeping the current one: {}",
+                    err
+                );
+                return;
+            }
+        };
+        let mut current = match self.current.write() {
+            Ok(current) => current,
+            Err(_) => {
+                log::error!("certificate lock is poisoned");
+                return;
+            }
+        };
+        if current.cert == certified_key.cert {
+            drop(current);
+            self.check_expiry(&info, false);
+            return;
+        }
+        *current = Arc::new(certified_key);
+        drop(current);
+        log::info!("reloaded tls certificate");
+        self.check_expiry(&info, true);
+    }
+
+    // warns for a new certificate that expires soon, and once a day while it keeps serving
+    fn check_expiry(&self, info: &ChainInfo, loaded: bool) {
+        if info.days_left > self.expiry_warning_days {
+            return;
+        }
+        let mut warned = match self.expiry_warned.lock() {
+            Ok(warned) => warned,
+            Err(_) => return,
+        };
+        if !loaded && warned.is_some_and(|at| at.elapsed() < EXPIRY_WARNING_INTERVAL) {
+            return;
+        }
+        *warned = Some(Instant::now());
+        log::warn!(
+            "certificate {} expires in {} days ({})",
+            info.subject,
+            info.days_left,
+            info.not_after
+        );
+    }
+}
+
+/// Reloads the certificate pair every interval (0 disables polling) and on SIGHUP
+pub async fn watch(
+    resolver: Arc<ReloadingCertResolver>,
+    certs: ImplCertificateInterface,
+    interval: u64,
+) -> io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    // the first tick completes immediately, the pair was just loaded
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = ticker.tick(), if interval > 0 => {}
+            _ = hangup.recv() => log::info!("SIGHUP received, reloading tls certificate"),
+        }
+        resolver.reload(&certs).await;
+    }
+}
+
+// reads the pair from the certificate source, checks the chain and that the key matches
+// the certificate
+async fn load(certs: &ImplCertificateInterface) -> io::Result<(CertifiedKey, ChainInfo)> {
+    let chain = certs.get_public_cert().await?;
+    let info = validate_chain(&chain)?;
+    let key = certs.get_private_cert().await?;
+    let provider = CryptoProvider::get_default()
+        .ok_or_else(|| error("no crypto provider installed".to_string()))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| error(format!("invalid private key: {}", e)))?;
+    let certified_key = CertifiedKey::new(chain, signing_key);
+    match certified_key.keys_match() {
+        Ok(()) | Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::Unknown)) => {}
+        Err(err) => {
+            return Err(error(format!(
+                "private key doesn't match the certificate {}: {}",
+                info.subject, err
+            )))
+        }
+    }
+    Ok((certified_key, info))
+}
@@ -1,37 +1,18 @@
+use crate::schema::FeatureSchema;
 use burn::{
     data::{dataloader::batcher::Batcher, dataset::Dataset, dataset::InMemDataset},
     prelude::*,
 };
+use std::collections::HashMap;
 
-pub const NUM_FEATURES: usize = 4;
-pub const NUM_CLASSES: usize = 3;
-
-// Pre-computed statistics for the vitalsign dataset features
-// Inputs are num_processors, avg_batch_size, queue_length, process_time
-const FEATURES_MIN: [f32; NUM_FEATURES] = [0.0, 0.0, 1.0, 1.0];
-const FEATURES_MAX: [f32; NUM_FEATURES] = [4.0, 1000.10, 100.0, 100.0];
-
+/// A queuemetrics row, features are in schema order
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct QueueMetrics {
-    /// processors
-    #[serde(rename = "processors")]
-    pub processors: f32,
-
-    /// batch size
-    #[serde(rename = "avg_batch_size")]
-    pub avg_batch_size: f32,
-
-    /// queue_length
-    #[serde(rename = "queue_length")]
-    pub queue_length: f32,
-
-    /// process_time
-    #[serde(rename = "processing_time")]
-    pub processing_time: f32,
+    /// feature values (raw, not normalized)
+    pub features: Vec<f32>,
 
     /// status - the label ;)
-    #[serde(rename = "status")]
-    pub status: f32,
+    pub label: f32,
 }
 
 pub struct QueueMetricsDataset {
@@ -39,29 +20,52 @@ pub struct QueueMetricsDataset {
 }
 
 impl QueueMetricsDataset {
-    pub fn train() -> Self {
-        Self::new("train")
+    pub fn train(schema: &FeatureSchema) -> Self {
+        Self::new("train", schema)
     }
 
-    pub fn validation() -> Self {
-        Self::new("validation")
+    pub fn validation(schema: &FeatureSchema) -> Self {
+        Self::new("validation", schema)
     }
 
-    pub fn test() -> Self {
-        Self::new("test")
+    pub fn test(schema: &FeatureSchema) -> Self {
+        Self::new("test", schema)
     }
 
-    pub fn new(split: &str) -> Self {
-        let mut rdr = csv::ReaderBuilder::new();
-        let rdr = rdr.delimiter(b',');
-        let file_name = match split.as_ref() {
+    pub fn new(split: &str, schema: &FeatureSchema) -> Self {
+        let file_name = match split {
             "train" => "data/queuemetrics-100000.csv",
             "validation" => "data/queuemetrics-20000.csv",
             "test" => "data/queuemetrics-1000.csv",
             _ => "data/queuemetrics-100000.csv",
         };
-        let dataset = InMemDataset::from_csv(file_name, &rdr).unwrap();
-        Self { dataset }
+        Self::from_csv(file_name, schema).unwrap()
+    }
+
+    /// Reads a csv file with a header row, the schema columns are looked up by name
+    pub fn from_csv(file_name: &str, schema: &FeatureSchema) -> Result<Self, String> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(b',')
+            .from_path(file_name)
+            .map_err(|e| format!("failed to open {}: {}", file_name, e))?;
+        let headers: HashMap<String, usize> = rdr
+            .headers()
+            .map_err(|e| format!("failed to read headers {}: {}", file_name, e))?
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.trim().to_string(), i))
+            .collect();
+        let mut items = Vec::new();
+        for (line, record) in rdr.records().enumerate() {
+            let record = record.map_err(|e| format!("{} line {}: {}", file_name, line + 2, e))?;
+            let item = schema
+                .parse_record(&headers, &record)
+                .map_err(|e| format!("{} line {}: {}", file_name, line + 2, e))?;
+            items.push(item);
+        }
+        Ok(Self {
+            dataset: InMemDataset::new(items),
+        })
     }
 }
 
@@ -79,21 +83,21 @@ impl Dataset<QueueMetrics> for QueueMetricsDataset {
 /// Normalizer for the metrics dataset.
 #[derive(Clone, Debug)]
 pub struct Normalizer<B: Backend> {
-    pub min: Tensor<B, 2>,
-    pub max: Tensor<B, 2>,
+    pub offset: Tensor<B, 2>,
+    pub scale: Tensor<B, 2>,
 }
 
 impl<B: Backend> Normalizer<B> {
     /// Creates a new normalizer.
-    pub fn new(device: &B::Device, min: &[f32], max: &[f32]) -> Self {
-        let min = Tensor::<B, 1>::from_floats(min, device).unsqueeze();
-        let max = Tensor::<B, 1>::from_floats(max, device).unsqueeze();
-        Self { min, max }
+    pub fn new(device: &B::Device, offset: &[f32], scale: &[f32]) -> Self {
+        let offset = Tensor::<B, 1>::from_floats(offset, device).unsqueeze();
+        let scale = Tensor::<B, 1>::from_floats(scale, device).unsqueeze();
+        Self { offset, scale }
     }
 
-    /// Normalizes the input according to the vital signs data set min/max.
+    /// Normalizes the input according to the schema offset/scale per feature.
     pub fn normalize(&self, input: Tensor<B, 2>) -> Tensor<B, 2> {
-        (input - self.min.clone()) / (self.max.clone() - self.min.clone())
+        (input - self.offset.clone()) / self.scale.clone()
     }
 }
 
@@ -101,49 +105,105 @@ impl<B: Backend> Normalizer<B> {
 pub struct QueueMetricsBatcher<B: Backend> {
     device: B::Device,
     normalizer: Normalizer<B>,
+    schema: FeatureSchema,
 }
 
 #[derive(Clone, Debug)]
 pub struct QueueMetricsBatch<B: Backend> {
     pub inputs: Tensor<B, 2>,
+    /// category indexes for the model embedding tables (embedding encoded features only)
+    pub categories: Option<Tensor<B, 2, Int>>,
     pub targets: Tensor<B, 1, Int>,
 }
 
 impl<B: Backend> QueueMetricsBatcher<B> {
-    pub fn new(device: B::Device) -> Self {
+    pub fn new(device: B::Device, schema: &FeatureSchema) -> Self {
+        let (offset, scale) = schema.normalization();
         Self {
             device: device.clone(),
-            normalizer: Normalizer::new(&device, &FEATURES_MIN, &FEATURES_MAX),
+            normalizer: Normalizer::new(&device, &offset, &scale),
+            schema: schema.clone(),
         }
     }
 }
 
-impl<B: Backend> Batcher<B, QueueMetrics, QueueMetricsBatch<B>> for QueueMetricsBatcher<B> {
-    fn batch(&self, items: Vec<QueueMetrics>, device: &B::Device) -> QueueMetricsBatch<B> {
+impl<B: Backend> QueueMetricsBatcher<B> {
+    /// Original batching, one tensor per row concatenated, kept to compare with in the benchmark
+    pub fn batch_per_row(
+        &self,
+        items: Vec<QueueMetrics>,
+        device: &B::Device,
+    ) -> QueueMetricsBatch<B> {
         let mut inputs: Vec<Tensor<B, 2>> = Vec::new();
+        let mut categories: Vec<Tensor<B, 2, Int>> = Vec::new();
         for item in items.iter() {
-            let input_tensor = Tensor::<B, 1>::from_floats(
-                [
-                    item.processors,
-                    item.avg_batch_size,
-                    item.queue_length,
-                    item.processing_time,
-                ],
-                &self.device,
-            );
+            let mut row = Vec::new();
+            let mut row_categories = Vec::new();
+            self.schema.encode(item, &mut row, &mut row_categories);
+            let input_tensor = Tensor::<B, 1>::from_floats(row.as_slice(), &self.device);
 
             inputs.push(input_tensor.unsqueeze());
+            if !row_categories.is_empty() {
+                let category_tensor =
+                    Tensor::<B, 1, Int>::from_data(row_categories.as_slice(), &self.device);
+                categories.push(category_tensor.unsqueeze());
+            }
         }
 
         let inputs = Tensor::cat(inputs, 0);
         let inputs = self.normalizer.normalize(inputs);
+        let categories = if categories.is_empty() {
+            None
+        } else {
+            Some(Tensor::cat(categories, 0))
+        };
         let mut targets: Vec<Tensor<B, 1, Int>> = Vec::new();
         for item in items.iter() {
-            let target_tensor = Tensor::<B, 1, Int>::from_data([item.status], device);
+            let target_tensor = Tensor::<B, 1, Int>::from_data([item.label], device);
             targets.push(target_tensor.unsqueeze());
         }
 
         let targets = Tensor::cat(targets, 0);
-        QueueMetricsBatch { inputs, targets }
+        QueueMetricsBatch {
+            inputs,
+            categories,
+            targets,
+        }
+    }
+}
+
+impl<B: Backend> Batcher<B, QueueMetrics, QueueMetricsBatch<B>> for QueueMetricsBatcher<B> {
+    // rows are encoded into flat buffers, each tensor is created with a single upload
+    fn batch(&self, items: Vec<QueueMetrics>, device: &B::Device) -> QueueMetricsBatch<B> {
+        let batch_size = items.len();
+        let width = self.schema.input_size();
+        let mut inputs: Vec<f32> = Vec::with_capacity(batch_size * width);
+        let mut categories: Vec<i64> = Vec::new();
+        let mut targets: Vec<i64> = Vec::with_capacity(batch_size);
+        for item in items.iter() {
+            self.schema.encode(item, &mut inputs, &mut categories);
+            targets.push(item.label as i64);
+        }
+
+        let inputs =
+            Tensor::<B, 2>::from_data(TensorData::new(inputs, [batch_size, width]), &self.device);
+        let inputs = self.normalizer.normalize(inputs);
+        let categories = if categories.is_empty() {
+            None
+        } else {
+            let count = categories.len() / batch_size;
+            Some(Tensor::<B, 2, Int>::from_data(
+                TensorData::new(categories, [batch_size, count]),
+                &self.device,
+            ))
+        };
+        let targets =
+            Tensor::<B, 1, Int>::from_data(TensorData::new(targets, [batch_size]), device);
+
+        QueueMetricsBatch {
+            inputs,
+            categories,
+            targets,
+        }
     }
 }
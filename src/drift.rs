@@ -0,0 +1,543 @@
+use crate::certhandler::error;
+use crate::dataset::QueueMetrics;
+use crate::metrics;
+use crate::schema::{FeatureSchema, FeatureType};
+use crate::serverconfig::Drift;
+
+use custom_logger as log;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+// numeric features are split into this many quantile bins of the training split
+const QUANTILE_BINS: usize = 10;
+// scores are recomputed (and published) every this many requests
+const EVALUATE_EVERY: u64 = 100;
+// no scores until the window has this many values
+const MIN_SAMPLES: usize = 100;
+// floor for empty bins, keeps psi finite
+const EPSILON: f64 = 1e-4;
+
+/// Training split distribution of one feature
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FeatureStats {
+    pub name: String,
+    #[serde(default)]
+    pub categorical: bool,
+    /// upper bounds of the quantile bins but the last (numeric features only)
+    pub edges: Vec<f32>,
+    /// share of training rows per bin, or per category
+    pub proportions: Vec<f64>,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Per feature distributions of the training split, saved as training-stats.json in the artifact dir
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrainingStats {
+    pub rows: usize,
+    pub features: Vec<FeatureStats>,
+}
+
+/// Drift scores of one feature over the current window, scores are None until there are enough
+/// values (ks and out of range rate are numeric only). ks is the largest gap between the training
+/// and live cdf at the training quantile bin edges, a binned distance rather than a KS test on the
+/// raw values
+#[derive(Clone, Debug, Serialize)]
+pub struct FeatureDrift {
+    pub feature: String,
+    pub samples: usize,
+    pub psi: Option<f64>,
+    pub ks: Option<f64>,
+    pub out_of_range_rate: Option<f64>,
+    pub drifting: bool,
+}
+
+impl TrainingStats {
+    pub fn from_items(schema: &FeatureSchema, items: &[QueueMetrics]) -> Self {
+        let features = schema
+            .features
+            .iter()
+            .enumerate()
+            .map(|(index, feature)| {
+                let mut values: Vec<f32> = items.iter().map(|item| item.features[index]).collect();
+                values.sort_by(|a, b| a.total_cmp(b));
+                let categorical = feature.feature_type == FeatureType::Categorical;
+                let mut edges = Vec::new();
+                if !categorical && !values.is_empty() {
+                    for bin in 1..QUANTILE_BINS {
+                        let edge = values[bin * values.len() / QUANTILE_BINS];
+                        // ties collapse bins, the edges stay increasing
+                        if edges.last().is_none_or(|last| edge > *last) {
+                            edges.push(edge);
+                        }
+                    }
+                }
+                let mut stats = FeatureStats {
+                    name: feature.name.clone(),
+                    categorical,
+                    proportions: vec![0.0; edges.len() + 1],
+                    edges,
+                    min: values.first().cloned().unwrap_or(0.0),
+                    max: values.last().cloned().unwrap_or(0.0),
+                };
+                if categorical {
+                    stats.proportions = vec![0.0; feature.categories.len().max(1)];
+                }
+                let mut counts = vec![0usize; stats.proportions.len()];
+                for value in values.iter() {
+                    counts[stats.bin(*value)] += 1;
+                }
+                stats.proportions = counts
+                    .iter()
+                    .map(|count| *count as f64 / values.len().max(1) as f64)
+                    .collect();
+                stats
+            })
+            .collect();
+        TrainingStats {
+            rows: items.len(),
+            features,
+        }
+    }
+
+    pub fn save(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let file = File::create(name)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// reads the stats saved with the model, None for artifacts trained before they were saved
+    pub fn read_artifacts(artifact_dir: &str) -> Option<TrainingStats> {
+        let name = format!("{artifact_dir}/training-stats.json");
+        let data = std::fs::read(&name).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(stats) => Some(stats),
+            Err(err) => {
+                log::warn!("ignoring {}: {}", name, err);
+                None
+            }
+        }
+    }
+}
+
+impl FeatureStats {
+    fn bin(&self, value: f32) -> usize {
+        if self.categorical {
+            return (value.max(0.0) as usize).min(self.proportions.len() - 1);
+        }
+        self.edges.partition_point(|edge| *edge < value)
+    }
+
+    fn out_of_range(&self, value: f32) -> bool {
+        !self.categorical && (value < self.min || value > self.max)
+    }
+}
+
+// the last window values of a feature as bins, with the running counts
+struct FeatureWindow {
+    values: VecDeque<(usize, bool)>,
+    counts: Vec<u64>,
+    out_of_range: u64,
+}
+
+impl FeatureWindow {
+    fn new(stats: &FeatureStats, size: usize) -> Self {
+        FeatureWindow {
+            values: VecDeque::with_capacity(size),
+            counts: vec![0; stats.proportions.len()],
+            out_of_range: 0,
+        }
+    }
+
+    // adds the value, dropping the oldest one beyond size
+    fn push(&mut self, stats: &FeatureStats, value: f32, size: usize) {
+        let bin = stats.bin(value);
+        let out_of_range = stats.out_of_range(value);
+        self.values.push_back((bin, out_of_range));
+        self.counts[bin] += 1;
+        self.out_of_range += out_of_range as u64;
+        if self.values.len() > size {
+            if let Some((bin, out_of_range)) = self.values.pop_front() {
+                self.counts[bin] -= 1;
+                self.out_of_range -= out_of_range as u64;
+            }
+        }
+    }
+}
+
+struct DriftState {
+    stats: TrainingStats,
+    windows: Vec<FeatureWindow>,
+    observed: u64,
+    report: Vec<FeatureDrift>,
+}
+
+static CONFIG: OnceLock<Drift> = OnceLock::new();
+static STATE: Mutex<Option<DriftState>> = Mutex::new(None);
+
+/// Sets the window and thresholds, fails when detection is enabled with a window shorter than the
+/// values needed for a score or a threshold that isn't above 0
+pub fn configure(config: Drift) -> io::Result<()> {
+    if config.enabled {
+        if config.window < MIN_SAMPLES {
+            return Err(error(format!(
+                "drift window must be at least {}, found {}",
+                MIN_SAMPLES, config.window
+            )));
+        }
+        for (name, threshold) in [
+            ("psi_threshold", config.psi_threshold),
+            ("ks_threshold", config.ks_threshold),
+            ("out_of_range_threshold", config.out_of_range_threshold),
+        ] {
+            if threshold.is_nan() || threshold <= 0.0 {
+                return Err(error(format!(
+                    "drift {} must be above 0, found {}",
+                    name, threshold
+                )));
+            }
+        }
+    }
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+/// Starts comparing requests with the stats of the loaded model, None turns detection off
+pub fn set_training_stats(stats: Option<TrainingStats>) {
+    let config = CONFIG.get().cloned().unwrap_or_default();
+    let Ok(mut state) = STATE.lock() else {
+        return;
+    };
+    *state = match stats {
+        Some(stats) if config.enabled => {
+            log::info!(
+                "drift detection : window {} : psi > {} : ks > {} : out of range > {}",
+                config.window,
+                config.psi_threshold,
+                config.ks_threshold,
+                config.out_of_range_threshold
+            );
+            let windows = stats
+                .features
+                .iter()
+                .map(|feature| FeatureWindow::new(feature, config.window))
+                .collect();
+            Some(DriftState {
+                stats,
+                windows,
+                observed: 0,
+                report: Vec::new(),
+            })
+        }
+        Some(_) => None,
+        None => {
+            if config.enabled {
+                log::info!("no training stats saved with the model, drift detection is off");
+            }
+            None
+        }
+    };
+}
+
+/// Adds the raw feature values of a request to the rolling windows
+pub fn observe(features: &[f32]) {
+    let config = CONFIG.get().cloned().unwrap_or_default();
+    let Ok(mut guard) = STATE.lock() else {
+        return;
+    };
+    let Some(state) = guard.as_mut() else {
+        return;
+    };
+    for ((stats, window), value) in state
+        .stats
+        .features
+        .iter()
+        .zip(state.windows.iter_mut())
+        .zip(features.iter())
+    {
+        window.push(stats, *value, config.window);
+    }
+    state.observed += 1;
+    if state.observed % EVALUATE_EVERY == 0 {
+        evaluate(state, &config);
+    }
+}
+
+/// The latest drift scores, None when detection is off
+pub fn report() -> Option<Vec<FeatureDrift>> {
+    let state = STATE.lock().ok()?;
+    state.as_ref().map(|state| state.report.clone())
+}
+
+// recomputes the scores, publishes them and logs features that start or stop drifting
+fn evaluate(state: &mut DriftState, config: &Drift) {
+    let mut report = Vec::with_capacity(state.windows.len());
+    for (index, (stats, window)) in state
+        .stats
+        .features
+        .iter()
+        .zip(state.windows.iter())
+        .enumerate()
+    {
+        let samples = window.values.len();
+        let mut drift = FeatureDrift {
+            feature: stats.name.clone(),
+            samples,
+            psi: None,
+            ks: None,
+            out_of_range_rate: None,
+            drifting: false,
+        };
+        if samples >= MIN_SAMPLES {
+            let live: Vec<f64> = window
+                .counts
+                .iter()
+                .map(|count| *count as f64 / samples as f64)
+                .collect();
+            drift.psi = Some(psi(&stats.proportions, &live));
+            if !stats.categorical {
+                drift.ks = Some(ks(&stats.proportions, &live));
+                drift.out_of_range_rate = Some(window.out_of_range as f64 / samples as f64);
+            }
+            drift.drifting = drift.psi.unwrap_or(0.0) > config.psi_threshold
+                || drift.ks.unwrap_or(0.0) > config.ks_threshold
+                || drift.out_of_range_rate.unwrap_or(0.0) > config.out_of_range_threshold;
+            metrics::set_feature_drift(
+                &stats.name,
+                drift.psi.unwrap_or(0.0),
+                drift.ks,
+                drift.out_of_range_rate,
+            );
+        }
+        let was_drifting = state
+            .report
+            .get(index)
+            .is_some_and(|previous| previous.drifting);
+        if drift.drifting && !was_drifting {
+            log::warn!(
+                "input drift on {} over the last {} requests : psi {:.3} : ks {:.3} : out of range {:.1}%",
+                stats.name,
+                samples,
+                drift.psi.unwrap_or(0.0),
+                drift.ks.unwrap_or(0.0),
+                drift.out_of_range_rate.unwrap_or(0.0) * 100.0
+            );
+        } else if !drift.drifting && was_drifting {
+            log::info!("input drift on {} cleared", stats.name);
+        }
+        report.push(drift);
+    }
+    state.report = report;
+}
+
+// population stability index of the live bin shares against the training shares
+fn psi(expected: &[f64], actual: &[f64]) -> f64 {
+    expected
+        .iter()
+        .zip(actual.iter())
+        .map(|(e, a)| {
+            let (e, a) = (e.max(EPSILON), a.max(EPSILON));
+            (a - e) * (a / e).ln()
+        })
+        .sum()
+}
+
+// largest gap between the cumulative bin shares, the kolmogorov-smirnov distance evaluated only at
+// the training quantile bin edges (a lower bound of the distance on the raw values)
+fn ks(expected: &[f64], actual: &[f64]) -> f64 {
+    let (mut e_cdf, mut a_cdf, mut max) = (0.0, 0.0, 0.0f64);
+    for (e, a) in expected.iter().zip(actual.iter()) {
+        e_cdf += e;
+        a_cdf += a;
+        max = max.max((e_cdf - a_cdf).abs());
+    }
+    max
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> FeatureSchema {
+        serde_json::from_value(serde_json::json!({
+            "features": [
+                { "name": "queue_length" },
+                { "name": "region", "type": "categorical", "categories": ["eu", "us"] }
+            ],
+            "label": "status",
+            "num_classes": 3
+        }))
+        .unwrap()
+    }
+
+    // queue_length 0..1000 uniform, region 3 eu for every us
+    fn training_stats() -> TrainingStats {
+        let items: Vec<QueueMetrics> = (0..1000)
+            .map(|i| QueueMetrics {
+                features: vec![i as f32, if i % 4 == 0 { 1.0 } else { 0.0 }],
+                label: 0.0,
+            })
+            .collect();
+        TrainingStats::from_items(&schema(), &items)
+    }
+
+    // scores of a window filled with the rows
+    fn scores(rows: impl Iterator<Item = [f32; 2]>) -> Vec<FeatureDrift> {
+        let config = Drift::default();
+        let stats = training_stats();
+        let mut windows: Vec<FeatureWindow> = stats
+            .features
+            .iter()
+            .map(|feature| FeatureWindow::new(feature, config.window))
+            .collect();
+        for row in rows {
+            for ((stats, window), value) in stats.features.iter().zip(windows.iter_mut()).zip(row) {
+                window.push(stats, value, config.window);
+            }
+        }
+        let mut state = DriftState {
+            stats,
+            windows,
+            observed: 0,
+            report: Vec::new(),
+        };
+        evaluate(&mut state, &config);
+        state.report
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn bins_the_training_split_by_quantiles() {
+        let stats = training_stats();
+        let numeric = &stats.features[0];
+        assert_eq!(stats.rows, 1000);
+        assert_eq!(
+            numeric.edges,
+            vec![100.0, 200.0, 300.0, 400.0, 500.0, 600.0, 700.0, 800.0, 900.0]
+        );
+        // the edges are upper bounds, 100 is in the first bin
+        let mut expected = vec![0.1; 10];
+        expected[0] = 0.101;
+        expected[9] = 0.099;
+        for (proportion, expected) in numeric.proportions.iter().zip(expected) {
+            assert_close(*proportion, expected);
+        }
+        assert_eq!((numeric.min, numeric.max), (0.0, 999.0));
+        let categorical = &stats.features[1];
+        assert!(categorical.categorical && categorical.edges.is_empty());
+        assert_eq!(categorical.proportions, vec![0.75, 0.25]);
+    }
+
+    #[test]
+    fn scores_known_distributions() {
+        assert_close(psi(&[0.5, 0.5], &[0.5, 0.5]), 0.0);
+        // (0.25 - 0.5) ln(0.5) + (0.75 - 0.5) ln(1.5)
+        assert_close(
+            psi(&[0.5, 0.5], &[0.25, 0.75]),
+            0.25 * 2f64.ln() + 0.25 * 1.5f64.ln(),
+        );
+        // empty live bins are floored at EPSILON, the score stays finite
+        assert!(psi(&[0.5, 0.5], &[1.0, 0.0]).is_finite());
+        assert_close(ks(&[0.25, 0.25, 0.5], &[0.25, 0.25, 0.5]), 0.0);
+        assert_close(ks(&[0.5, 0.5], &[0.25, 0.75]), 0.25);
+        // the largest gap over the cumulative shares, not over single bins
+        assert_close(ks(&[0.2, 0.2, 0.2, 0.4], &[0.1, 0.1, 0.1, 0.7]), 0.3);
+    }
+
+    #[test]
+    fn doesnt_flag_requests_like_the_training_split() {
+        let report = scores((0..1000).map(|i| [i as f32, if i % 4 == 0 { 1.0 } else { 0.0 }]));
+        let numeric = &report[0];
+        assert_eq!(numeric.samples, 1000);
+        assert_close(numeric.psi.unwrap(), 0.0);
+        assert_close(numeric.ks.unwrap(), 0.0);
+        assert_eq!(numeric.out_of_range_rate, Some(0.0));
+        assert!(!numeric.drifting);
+        let categorical = &report[1];
+        assert_close(categorical.psi.unwrap(), 0.0);
+        assert_eq!(
+            (categorical.ks, categorical.out_of_range_rate),
+            (None, None)
+        );
+        assert!(!categorical.drifting);
+    }
+
+    #[test]
+    fn flags_a_shifted_feature() {
+        // queue_length moved up by half its range, half the values are beyond the training max
+        let report = scores((0..1000).map(|i| [i as f32 + 500.0, 0.0]));
+        let numeric = &report[0];
+        assert_close(numeric.ks.unwrap(), 0.5);
+        assert_close(numeric.out_of_range_rate.unwrap(), 0.5);
+        assert!(numeric.psi.unwrap() > 1.0);
+        assert!(numeric.drifting);
+        // every request in eu against 75% in training
+        let categorical = &report[1];
+        assert_close(
+            categorical.psi.unwrap(),
+            0.25 * (1.0 / 0.75f64).ln() + (0.25 - EPSILON) * (0.25 / EPSILON).ln(),
+        );
+        assert!(categorical.drifting);
+    }
+
+    #[test]
+    fn needs_min_samples_before_scoring() {
+        let report = scores((0..MIN_SAMPLES - 1).map(|i| [i as f32 * 1000.0, 0.0]));
+        assert_eq!(report[0].samples, MIN_SAMPLES - 1);
+        assert_eq!(report[0].psi, None);
+        assert!(!report[0].drifting);
+    }
+
+    #[test]
+    fn keeps_the_last_window_values() {
+        let stats = &training_stats().features[0];
+        let mut window = FeatureWindow::new(stats, 3);
+        for value in [50.0, 2000.0, 150.0, 250.0] {
+            window.push(stats, value, 3);
+        }
+        let bins: Vec<usize> = window.values.iter().map(|(bin, _)| *bin).collect();
+        assert_eq!(bins, vec![9, 1, 2]);
+        assert_eq!(window.counts[0], 0);
+        assert_eq!(window.out_of_range, 1);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        for (config, message) in [
+            (
+                Drift {
+                    window: 50,
+                    ..Drift::default()
+                },
+                "drift window must be at least 100, found 50",
+            ),
+            (
+                Drift {
+                    ks_threshold: 0.0,
+                    ..Drift::default()
+                },
+                "drift ks_threshold must be above 0, found 0",
+            ),
+            (
+                Drift {
+                    psi_threshold: f64::NAN,
+                    ..Drift::default()
+                },
+                "drift psi_threshold must be above 0, found NaN",
+            ),
+        ] {
+            assert_eq!(configure(config).unwrap_err().to_string(), message);
+        }
+    }
+}
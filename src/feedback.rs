@@ -0,0 +1,332 @@
+use crate::certhandler::error;
+use crate::metrics;
+use crate::predictionlog::{self, LoggedPrediction};
+use crate::schema::{FeatureSchema, FieldError};
+use crate::serviceerror::ServiceError;
+
+use custom_logger as log;
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Labeled rows are appended to labeled-<model version>.csv in the prediction log dir, a file per
+/// model so the header matches the schema of every row
+pub const LABELED_PREFIX: &str = "labeled-";
+
+// (correct, total) feedback per model version since startup
+static ACCURACY: Mutex<BTreeMap<String, (u64, u64)>> = Mutex::new(BTreeMap::new());
+// request ids in each labeled file, read from the file the first time it's used so repeated
+// feedback is refused across restarts. The lock serializes the check and the append
+static LABELED: Mutex<Option<HashMap<PathBuf, HashSet<String>>>> = Mutex::new(None);
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct FeedbackResponse {
+    pub request_id: String,
+    pub model_version: String,
+    pub predicted: usize,
+    pub actual: usize,
+    pub correct: bool,
+}
+
+/// Reads the request id and the true label (class index or name) from a feedback body
+pub fn parse(schema: &FeatureSchema, data: &Value) -> Result<(String, usize), ServiceError> {
+    let invalid = |field: &str, reason: &str| {
+        ServiceError::BadRequest(
+            "invalid feedback".to_string(),
+            vec![FieldError {
+                field: field.to_string(),
+                reason: reason.to_string(),
+            }],
+        )
+    };
+    let request_id = match data.get("request_id").and_then(Value::as_str) {
+        Some(id) if !id.trim().is_empty() => id.trim().to_string(),
+        _ => return Err(invalid("request_id", "is missing")),
+    };
+    let class_names = schema.class_names();
+    let actual = match data.get(&schema.label) {
+        Some(Value::Number(n)) => n
+            .as_u64()
+            .map(|n| n as usize)
+            .filter(|n| *n < schema.num_classes),
+        Some(Value::String(name)) => class_names.iter().position(|c| c == name),
+        _ => return Err(invalid(&schema.label, "is missing")),
+    };
+    match actual {
+        Some(actual) => Ok((request_id, actual)),
+        None => Err(invalid(
+            &schema.label,
+            &format!("must be a class index or one of {}", class_names.join(", ")),
+        )),
+    }
+}
+
+/// Joins the feedback with the logged prediction, appends the labeled row and updates the live
+/// accuracy (blocking, the prediction log files may be read)
+pub fn record(
+    schema: &FeatureSchema,
+    request_id: &str,
+    actual: usize,
+) -> Result<FeedbackResponse, ServiceError> {
+    let dir = predictionlog::dir().ok_or(ServiceError::Unavailable(
+        "feedback needs the prediction_log to be configured".to_string(),
+    ))?;
+    let logged = predictionlog::find(request_id, schema)
+        .map_err(|e| ServiceError::Internal(format!("prediction log: {}", e)))?
+        .ok_or(ServiceError::NotFound(format!(
+            "prediction for request {}",
+            request_id
+        )))?;
+    let appended = append_labeled(&dir, request_id, &logged, actual)
+        .map_err(|e| ServiceError::Internal(format!("labeled dataset: {}", e)))?;
+    if !appended {
+        return Err(ServiceError::Conflict(format!(
+            "feedback for {} was already recorded",
+            request_id
+        )));
+    }
+    let correct = logged.predicted == actual;
+    observe(&logged.model_version, correct);
+    log::debug!(
+        "feedback {} : predicted {} : actual {}",
+        request_id,
+        logged.predicted,
+        actual
+    );
+    Ok(FeedbackResponse {
+        request_id: request_id.to_string(),
+        model_version: logged.model_version,
+        predicted: logged.predicted,
+        actual,
+        correct,
+    })
+}
+
+// the training csv columns with the label filled in, followed by the prediction details. False
+// when the file already has a row for the request id, the id only counts as labeled once its row
+// is written so a failed append can be retried
+fn append_labeled(
+    dir: &str,
+    request_id: &str,
+    logged: &LoggedPrediction,
+    actual: usize,
+) -> io::Result<bool> {
+    let mut labeled = LABELED
+        .lock()
+        .map_err(|_| error("labeled file lock is poisoned".to_string()))?;
+    let path = Path::new(dir).join(format!("{}{}.csv", LABELED_PREFIX, logged.model_version));
+    let ids = match labeled.get_or_insert_with(HashMap::new).entry(path.clone()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(labeled_ids(&path)?),
+    };
+    if ids.contains(request_id) {
+        return Ok(false);
+    }
+    let new_file = path.metadata().map(|m| m.len() == 0).unwrap_or(true);
+    let file = File::options().create(true).append(true).open(&path)?;
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(file);
+    if new_file {
+        let mut header: Vec<&str> = logged.columns.iter().map(String::as_str).collect();
+        header.extend([
+            logged.label.as_str(),
+            "request_id",
+            "model_version",
+            "predicted",
+        ]);
+        writer.write_record(&header)?;
+    }
+    let mut row = logged.features.clone();
+    row.extend([
+        actual.to_string(),
+        request_id.to_string(),
+        logged.model_version.clone(),
+        logged.predicted.to_string(),
+    ]);
+    writer.write_record(&row)?;
+    writer.flush()?;
+    ids.insert(request_id.to_string());
+    Ok(true)
+}
+
+// the request_id column of a labeled file, empty when there is no file yet
+fn labeled_ids(path: &Path) -> io::Result<HashSet<String>> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+    let mut rdr = csv::Reader::from_path(path)?;
+    let index = rdr
+        .headers()?
+        .iter()
+        .position(|name| name == "request_id")
+        .ok_or_else(|| error(format!("{} has no request_id column", path.display())))?;
+    let mut ids = HashSet::new();
+    for record in rdr.records() {
+        if let Some(id) = record?.get(index) {
+            ids.insert(id.to_string());
+        }
+    }
+    Ok(ids)
+}
+
+fn observe(model_version: &str, correct: bool) {
+    let Ok(mut accuracy) = ACCURACY.lock() else {
+        return;
+    };
+    let (hits, total) = accuracy.entry(model_version.to_string()).or_insert((0, 0));
+    *total += 1;
+    if correct {
+        *hits += 1;
+    }
+    metrics::observe_feedback(model_version, correct, *hits as f64 / *total as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::predictionlog::PredictionRecord;
+    use serde_json::json;
+    use std::fs;
+    use std::sync::Arc;
+
+    fn schema() -> Arc<FeatureSchema> {
+        Arc::new(
+            serde_json::from_value(json!({
+                "features": [
+                    { "name": "processors", "type": "int" },
+                    { "name": "region", "type": "categorical", "categories": ["eu", "us"] }
+                ],
+                "label": "status",
+                "num_classes": 3,
+                "class_names": ["ok", "slow", "down"]
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn invalid_field(result: Result<(String, usize), ServiceError>) -> (String, String) {
+        match result {
+            Err(ServiceError::BadRequest(_, errors)) => {
+                (errors[0].field.clone(), errors[0].reason.clone())
+            }
+            other => panic!("expected a 400, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_class_indices_and_names() {
+        let schema = schema();
+        assert_eq!(
+            parse(&schema, &json!({ "request_id": " r1 ", "status": 2 })).unwrap(),
+            ("r1".to_string(), 2)
+        );
+        assert_eq!(
+            parse(&schema, &json!({ "request_id": "r1", "status": "slow" })).unwrap(),
+            ("r1".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_feedback() {
+        let schema = schema();
+        let classes = "must be a class index or one of ok, slow, down";
+        for (body, field, reason) in [
+            (json!({ "status": 1 }), "request_id", "is missing"),
+            (
+                json!({ "request_id": " ", "status": 1 }),
+                "request_id",
+                "is missing",
+            ),
+            (json!({ "request_id": "r1" }), "status", "is missing"),
+            (
+                json!({ "request_id": "r1", "status": 3 }),
+                "status",
+                classes,
+            ),
+            (
+                json!({ "request_id": "r1", "status": -1 }),
+                "status",
+                classes,
+            ),
+            (
+                json!({ "request_id": "r1", "status": "up" }),
+                "status",
+                classes,
+            ),
+        ] {
+            assert_eq!(
+                invalid_field(parse(&schema, &body)),
+                (field.to_string(), reason.to_string())
+            );
+        }
+    }
+
+    // the prediction log is process wide, the whole feedback flow runs in one test
+    #[test]
+    fn records_feedback_once_per_prediction() {
+        let dir = std::env::temp_dir()
+            .join(format!("queuemetrics-feedback-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        predictionlog::init(
+            serde_json::from_value(json!({ "dir": dir, "format": "csv" })).unwrap(),
+        )
+        .unwrap();
+        let schema = schema();
+        predictionlog::write(PredictionRecord {
+            request_id: "fb-1".to_string(),
+            timestamp: "2026-10-19T09:12:03.51Z".to_string(),
+            model_version: "v1".to_string(),
+            schema: schema.clone(),
+            features: vec![4.0, 1.0],
+            predicted: 2,
+            probabilities: vec![0.1, 0.2, 0.7],
+        });
+
+        let unknown = record(&schema, "fb-unknown", 0);
+        assert!(
+            matches!(unknown, Err(ServiceError::NotFound(_))),
+            "{unknown:?}"
+        );
+
+        // a failed append doesn't count as labeled, the feedback can be sent again
+        let labeled = Path::new(&dir).join("labeled-v1.csv");
+        fs::create_dir_all(&labeled).unwrap();
+        let failed = record(&schema, "fb-1", 1);
+        assert!(
+            matches!(failed, Err(ServiceError::Internal(_))),
+            "{failed:?}"
+        );
+        fs::remove_dir(&labeled).unwrap();
+        let recorded = record(&schema, "fb-1", 1).unwrap();
+        assert_eq!(
+            (recorded.predicted, recorded.actual, recorded.correct),
+            (2, 1, false)
+        );
+
+        let repeated = record(&schema, "fb-1", 2);
+        assert!(
+            matches!(repeated, Err(ServiceError::Conflict(_))),
+            "{repeated:?}"
+        );
+        // after a restart the labeled file still has the row
+        *LABELED.lock().unwrap() = None;
+        let repeated = record(&schema, "fb-1", 2);
+        assert!(
+            matches!(repeated, Err(ServiceError::Conflict(_))),
+            "{repeated:?}"
+        );
+
+        let rows = fs::read_to_string(&labeled).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(
+            rows,
+            "processors,region,status,request_id,model_version,predicted\n4,us,1,fb-1,v1,2\n"
+        );
+    }
+}
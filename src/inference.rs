@@ -1,4 +1,5 @@
 use burn::{
+    config::Config,
     data::{dataloader::batcher::Batcher, dataset::Dataset},
     module::Module,
     record::{CompactRecorder, Recorder},
@@ -6,28 +7,31 @@ use burn::{
 };
 
 use crate::{
-    dataset::{QueueMetrics, QueueMetricsBatcher, QueueMetricsDataset, NUM_CLASSES, NUM_FEATURES},
-    model::{ModelConfig, ModelRecord},
+    dataset::{QueueMetrics, QueueMetricsBatcher, QueueMetricsDataset},
+    model::ModelRecord,
+    schema::FeatureSchema,
+    training::ExpConfig,
 };
 
 use custom_logger as log;
 
 pub fn infer<B: Backend>(artifacts_dir: &str, device: B::Device) {
-    // parameters are features,classes,hidden size
+    let config = ExpConfig::load(format!("{artifacts_dir}/config.json"))
+        .expect("Config should exist; run train first");
+    let schema = FeatureSchema::read_artifacts(artifacts_dir)
+        .expect("Schema should be valid; run train first");
     let record: ModelRecord<B> = CompactRecorder::new()
         .load(format!("{artifacts_dir}/model").into(), &device)
         .expect("Trained model should exist; run train first");
-    let model = ModelConfig::new(NUM_FEATURES, NUM_CLASSES, 256)
-        .init(&device)
-        .load_record(record);
+    let model = config.model.init(&device).load_record(record);
 
     // Use a sample of 10 items from the test split
-    let dataset = QueueMetricsDataset::test();
+    let dataset = QueueMetricsDataset::test(&schema);
     let items: Vec<QueueMetrics> = dataset.iter().take(1000).collect();
 
-    let batcher = QueueMetricsBatcher::new(device.clone());
+    let batcher = QueueMetricsBatcher::new(device.clone(), &schema);
     let batch = batcher.batch(items.clone(), &device);
-    let predicted = model.forward(batch.inputs.clone());
+    let predicted = model.forward(batch.inputs.clone(), batch.categories.clone());
     let targets = batch.targets;
 
     let expected = targets.into_data().iter::<f32>().collect::<Vec<_>>();
@@ -64,7 +68,7 @@ pub fn infer<B: Backend>(artifacts_dir: &str, device: B::Device) {
 fn find_max_index(input: &Vec<f32>) -> (usize, f32) {
     let mut max_index = 0;
     let mut index = 0;
-    let mut max = 0.0f32;
+    let mut max = f32::NEG_INFINITY;
     for item in input.iter() {
         if item > &max {
             max_index = index;
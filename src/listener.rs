@@ -0,0 +1,241 @@
+use crate::certhandler::ClientIdentity;
+use crate::server::inference_service;
+use crate::serverconfig::Limits;
+
+use custom_logger as log;
+use http::Request;
+use hyper::body::Incoming;
+use hyper::rt::{Read, Write};
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static TOTAL_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Set to true to stop the accept loops and close connections after their current request,
+/// every connection task holds a receiver so the sender can wait for them with closed()
+pub type Shutdown = watch::Receiver<bool>;
+
+/// Limits shared by all listeners, the connection permits are global
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    permits: Arc<Semaphore>,
+    limits: Limits,
+}
+
+impl ConnectionLimits {
+    pub fn new(limits: Limits) -> Self {
+        ConnectionLimits {
+            permits: Arc::new(Semaphore::new(limits.max_connections)),
+            limits,
+        }
+    }
+
+    // waits for a free connection slot, None when shutting down
+    async fn acquire(&self, shutdown: &mut Shutdown) -> Option<OwnedSemaphorePermit> {
+        if self.permits.available_permits() == 0 {
+            log::warn!(
+                "max connections ({}) reached, waiting for a connection to close",
+                self.limits.max_connections
+            );
+        }
+        tokio::select! {
+            permit = self.permits.clone().acquire_owned() => permit.ok(),
+            _ = shutdown.changed() => None,
+        }
+    }
+}
+
+// counts a connection as open until dropped, and holds its connection slot
+struct OpenConnection {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl OpenConnection {
+    fn new(permit: OwnedSemaphorePermit) -> Self {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::SeqCst);
+        TOTAL_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        OpenConnection { _permit: permit }
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::SeqCst)
+}
+
+pub fn total_connections() -> u64 {
+    TOTAL_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Waits for SIGTERM or SIGINT, returns the signal name
+pub async fn shutdown_signal() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+    }
+}
+
+/// Per connection details, added to the extensions of every request on the connection
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    /// the verified client certificate, when mTLS is configured and the client sent one
+    pub client: Option<ClientIdentity>,
+}
+
+impl ConnectionInfo {
+    // identity used in log lines, the certificate subject or the remote address
+    pub fn client_label(&self) -> String {
+        match &self.client {
+            Some(client) => client.subject.clone(),
+            None => self.remote_addr.to_string(),
+        }
+    }
+}
+
+/// Accepts https connections, h2 and http/1.1 are negotiated with alpn
+pub async fn serve_tls(
+    listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    limits: ConnectionLimits,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    loop {
+        let Some(permit) = limits.acquire(&mut shutdown).await else {
+            return Ok(());
+        };
+        let (tcp_stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let tls_acceptor = tls_acceptor.clone();
+        let limits = limits.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let _open = OpenConnection::new(permit);
+            let handshake_timeout = Duration::from_secs(limits.limits.tls_handshake_timeout);
+            let tls_stream = match tokio::time::timeout(
+                handshake_timeout,
+                tls_acceptor.accept(tcp_stream),
+            )
+            .await
+            {
+                Ok(Ok(tls_stream)) => tls_stream,
+                Ok(Err(err)) => {
+                    log::error!("failed to perform tls handshake with {remote_addr}: {err:#}");
+                    return;
+                }
+                Err(_) => {
+                    log::warn!("tls handshake with {remote_addr} timed out");
+                    return;
+                }
+            };
+            let client = match tls_stream.get_ref().1.peer_certificates() {
+                Some([cert, ..]) => match ClientIdentity::from_cert(cert) {
+                    Ok(client) => Some(client),
+                    Err(err) => {
+                        log::error!("client {remote_addr}: {err}");
+                        return;
+                    }
+                },
+                _ => None,
+            };
+            if let Some(client) = &client {
+                log::debug!(
+                    "client {} ({}) connected from {}",
+                    client.subject,
+                    client.san.join(", "),
+                    remote_addr
+                );
+            }
+            let info = ConnectionInfo {
+                remote_addr,
+                client,
+            };
+            serve_connection(TokioIo::new(tls_stream), info, &limits.limits, shutdown).await;
+        });
+    }
+}
+
+/// Accepts plain http connections, http/1.1 and h2c (prior knowledge) are detected per connection
+pub async fn serve_plain(
+    listener: TcpListener,
+    limits: ConnectionLimits,
+    mut shutdown: Shutdown,
+) -> io::Result<()> {
+    loop {
+        let Some(permit) = limits.acquire(&mut shutdown).await else {
+            return Ok(());
+        };
+        let (tcp_stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let limits = limits.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let _open = OpenConnection::new(permit);
+            let info = ConnectionInfo {
+                remote_addr,
+                client: None,
+            };
+            serve_connection(TokioIo::new(tcp_stream), info, &limits.limits, shutdown).await;
+        });
+    }
+}
+
+// on shutdown http/1 connections close after the current request and h2 connections send a
+// goaway, in both cases the requests in flight complete
+async fn serve_connection<I>(io: I, info: ConnectionInfo, limits: &Limits, mut shutdown: Shutdown)
+where
+    I: Read + Write + Unpin + Send + 'static,
+{
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(info.clone());
+        inference_service(req)
+    });
+    let mut builder = Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(limits.header_read_timeout));
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(limits.http2_max_concurrent_streams)
+        .max_header_list_size(limits.http2_max_header_list_size)
+        .keep_alive_interval(
+            (limits.http2_keep_alive_interval > 0)
+                .then(|| Duration::from_secs(limits.http2_keep_alive_interval)),
+        )
+        .keep_alive_timeout(Duration::from_secs(limits.http2_keep_alive_timeout));
+    let connection = builder.serve_connection(io, service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.changed() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        log::error!("failed to serve connection: {err:#}");
+    }
+}
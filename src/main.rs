@@ -1,29 +1,48 @@
-use crate::certhandler::{error, CertificateInterface, ImplCertificateInterface};
+use crate::certhandler::{CertificateInterface, ImplCertificateInterface};
+use crate::certreload::ReloadingCertResolver;
+use crate::schema::FeatureSchema;
 use crate::serverconfig::{ConfigInterface, ImplConfigInterface, Parameters};
 use burn_autodiff::Autodiff;
 use burn_cuda::{Cuda, CudaDevice};
 use clap::{Parser, Subcommand};
 use custom_logger as log;
-use hyper::service::service_fn;
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use hyper_util::server::conn::auto::Builder;
-use rustls::ServerConfig;
-use server::inference_service;
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
 use std::collections::HashMap;
 use std::env;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tokio_rustls::TlsAcceptor;
 
+mod accesslog;
+mod auth;
+mod batchqueue;
+mod bench;
+mod certgen;
 mod certhandler;
+mod certreload;
 mod dataset;
+mod drift;
+mod feedback;
 mod inference;
+mod listener;
+mod metrics;
 mod model;
+mod modelstore;
+mod predictionlog;
+mod ratelimit;
+mod schema;
 mod server;
 mod serverconfig;
+mod serviceerror;
+mod tlspolicy;
 mod training;
+mod tune;
 
 /// cli struct
 #[derive(Parser, Debug)]
@@ -48,6 +67,42 @@ pub enum Commands {
     Inference {},
     /// Serve inference subcommand (launches json web service)
     Serve {},
+    /// Hyperparameter search subcommand (grid or random search over a search space file)
+    Tune {
+        /// search space file to use
+        #[arg(short, long, value_name = "search-space")]
+        search_space: String,
+    },
+    /// Benchmark subcommand (compares per row and flat buffer batching throughput)
+    Bench {
+        /// rows per batch
+        #[arg(short, long, default_value_t = 1024)]
+        batch_size: usize,
+        /// batches per measurement
+        #[arg(short, long, default_value_t = 50)]
+        iterations: usize,
+    },
+    /// Certificate subcommands
+    Certs {
+        #[command(subcommand)]
+        command: CertsCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CertsCommands {
+    /// Generate a CA and a server key/cert pair signed by it into certs_dir
+    Generate {
+        /// hostname (dns name) the server certificate is valid for
+        #[arg(long, value_name = "hostname")]
+        hostname: String,
+        /// ip address the server certificate is valid for (can be repeated)
+        #[arg(long, value_name = "ip")]
+        ip: Vec<String>,
+        /// overwrite existing files
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
 }
 
 // used for lookup in read mode only
@@ -64,7 +119,54 @@ fn main() {
     match &args.command {
         Some(Commands::Train {}) => {
             type MyAutodiffBackend = Autodiff<MyBackend>;
-            training::run::<MyAutodiffBackend>(&params.artifacts_dir, device.clone());
+            let schema = read_schema(&params);
+            training::run::<MyAutodiffBackend>(&params.artifacts_dir, &schema, device.clone());
+        }
+        Some(Commands::Tune { search_space }) => {
+            type MyAutodiffBackend = Autodiff<MyBackend>;
+            let schema = read_schema(&params);
+            if let Err(e) = tune::run::<MyAutodiffBackend>(
+                &params.artifacts_dir,
+                search_space,
+                &schema,
+                device.clone(),
+            ) {
+                eprintln!("tune: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(Commands::Bench {
+            batch_size,
+            iterations,
+        }) => {
+            let schema = read_schema(&params);
+            bench::run::<MyBackend>(&schema, *batch_size, *iterations, device);
+        }
+        Some(Commands::Certs {
+            command:
+                CertsCommands::Generate {
+                    hostname,
+                    ip,
+                    force,
+                },
+        }) => {
+            let certs_dir = params.certs_dir.clone().unwrap_or_else(|| {
+                eprintln!("certs_dir must be set in the config file");
+                std::process::exit(1);
+            });
+            let generated = certgen::generate(hostname, ip)
+                .and_then(|certs| certgen::write(&certs_dir, &certs, *force));
+            match generated {
+                Ok(paths) => {
+                    for path in paths {
+                        println!("created {}", path);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("certs: {}", e);
+                    std::process::exit(1);
+                }
+            }
         }
         Some(Commands::Inference {}) => {
             // use logging only for the inference and web service
@@ -114,51 +216,205 @@ fn main() {
     }
 }
 
+// schema used for training, the artifacts keep a copy for inference and serve
+fn read_schema(params: &Parameters) -> FeatureSchema {
+    match &params.schema {
+        Some(name) => FeatureSchema::read(name).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }),
+        None => FeatureSchema::default(),
+    }
+}
+
 #[tokio::main]
 async fn run_server(params: Parameters) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let tls_enabled = match params.tls.as_deref() {
+        None | Some("enabled") => true,
+        Some("disabled") => false,
+        Some(other) => {
+            return Err(Box::from(format!(
+                "tls must be enabled or disabled, found {}",
+                other
+            )))
+        }
+    };
+    // the plain listener has no client certificates, it would serve every route without them
+    if let Some(client_auth) = &params.client_auth {
+        if !tls_enabled {
+            return Err(Box::from("client_auth needs tls enabled"));
+        }
+        if client_auth.mode == "required" && params.http_port.is_some() {
+            return Err(Box::from(
+                "client_auth mode required can't be combined with http_port",
+            ));
+        }
+    }
     let addr = SocketAddr::new(
         Ipv4Addr::new(0, 0, 0, 0).into(),
         params.port.parse().unwrap(),
     );
+    let tls_policy = params.tls_policy.clone().unwrap_or_default();
+    let limits = params.limits.clone().unwrap_or_default();
+    if limits.max_connections == 0 {
+        return Err(Box::from("limits max_connections must be at least 1"));
+    }
+    server::set_body_limits(
+        limits.max_body_size,
+        Duration::from_secs(limits.body_read_timeout),
+    );
+    let connection_limits = listener::ConnectionLimits::new(limits);
+    let batching = params.batching.clone().unwrap_or_default();
+    if batching.max_batch_size == 0 {
+        return Err(Box::from("batching max_batch_size must be at least 1"));
+    }
+    let inference_pool = params.inference_pool.clone().unwrap_or_default();
+    if inference_pool.workers == 0 || inference_pool.queue_size == 0 {
+        return Err(Box::from(
+            "inference_pool workers and queue_size must be at least 1",
+        ));
+    }
+    drift::configure(params.drift.clone().unwrap_or_default())?;
+    accesslog::set_enabled(params.access_log.unwrap_or(true));
+    if let Some(auth) = params.auth.clone() {
+        auth::init(auth)?;
+    }
+    if let Some(prediction_log) = params.prediction_log.clone() {
+        predictionlog::init(prediction_log)?;
+    }
+    if let Some(rate_limits) = params.rate_limits.clone() {
+        ratelimit::init(rate_limits)?;
+    }
+    server::set_http10_allowed(tls_policy.alpn.iter().any(|p| p == "http/1.0"));
 
-    rustls::crypto::ring::default_provider()
-        .install_default()
-        .expect("Failed to install rustls crypto provider");
-    let certs_dir = params.certs_dir.unwrap_or("".to_string()).to_string();
-    let impl_certs = ImplCertificateInterface::new(params.cert_mode, Some(certs_dir));
-    // Load public certificate.
-    let certs = impl_certs.get_public_cert().await.unwrap();
-    // Load private key.
-    let key = impl_certs.get_private_cert().await.unwrap();
-    log::info!("starting {} on https://{}", params.name, addr);
-    // Create a TCP listener via tokio.
-    let incoming = TcpListener::bind(&addr).await?;
-    // Build TLS configuration.
-    let mut server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|e| error(e.to_string()))?;
-    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
-    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
-    let service = service_fn(inference_service);
-
-    loop {
-        let (tcp_stream, _remote_addr) = incoming.accept().await?;
-        let tls_acceptor = tls_acceptor.clone();
-        tokio::spawn(async move {
-            let tls_stream = match tls_acceptor.accept(tcp_stream).await {
-                Ok(tls_stream) => tls_stream,
-                Err(err) => {
-                    log::error!("failed to perform tls handshake: {err:#}");
-                    return;
+    let mut listeners = JoinSet::new();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    if tls_enabled {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .expect("Failed to install rustls crypto provider");
+        let impl_certs = ImplCertificateInterface::new(params.cert_mode, params.certs_dir)
+            .with_key_passphrase(params.key_passphrase);
+        // Load and validate the certificate pair, it's reloaded when it changes.
+        let expiry_warning_days = params.cert_expiry_warning_days.unwrap_or(30);
+        let resolver =
+            Arc::new(ReloadingCertResolver::new(&impl_certs, expiry_warning_days).await?);
+        let provider = tlspolicy::provider(&tls_policy)?;
+        let client_verifier = match &params.client_auth {
+            None => WebPkiClientVerifier::no_client_auth(),
+            Some(client_auth) => {
+                let mut roots = RootCertStore::empty();
+                for cert in impl_certs.get_client_ca(&client_auth.ca_bundle).await? {
+                    roots.add(cert)?;
                 }
-            };
-            if let Err(err) = Builder::new(TokioExecutor::new())
-                .serve_connection(TokioIo::new(tls_stream), service)
-                .await
-            {
-                log::error!("failed to serve connection: {err:#}");
+                let builder =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+                let verifier = match client_auth.mode.as_str() {
+                    "required" => builder.build()?,
+                    "optional" => builder.allow_unauthenticated().build()?,
+                    other => {
+                        return Err(Box::from(format!(
+                            "client_auth mode must be optional or required, found {}",
+                            other
+                        )))
+                    }
+                };
+                server::set_allowed_clients(client_auth.allowed.clone());
+                log::info!(
+                    "client certificates are {} ({})",
+                    client_auth.mode,
+                    client_auth.ca_bundle
+                );
+                verifier
+            }
+        };
+        // Build TLS configuration.
+        let server_config =
+            tlspolicy::server_config(&tls_policy, provider, client_verifier, resolver.clone())?;
+        log::info!(
+            "tls {}+ : alpn {} : cipher suites {}",
+            tls_policy.min_version,
+            tls_policy.alpn.join(","),
+            if tls_policy.cipher_suites.is_empty() {
+                "default".to_string()
+            } else {
+                tls_policy.cipher_suites.join(",")
+            }
+        );
+        let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+        // Create a TCP listener via tokio.
+        let incoming = TcpListener::bind(&addr).await?;
+        log::info!("starting {} on https://{}", params.name, addr);
+        listeners.spawn(listener::serve_tls(
+            incoming,
+            tls_acceptor,
+            connection_limits.clone(),
+            shutdown_rx.clone(),
+        ));
+        let interval = params.cert_reload_interval.unwrap_or(60);
+        tokio::spawn(async move {
+            if let Err(e) = certreload::watch(resolver, impl_certs, interval).await {
+                log::error!("certificate reload stopped: {}", e);
             }
         });
     }
+    // plain http/1.1 and h2c, either instead of https or next to it on http_port
+    let http_port = match (tls_enabled, &params.http_port) {
+        (false, _) => Some(params.port.clone()),
+        (true, Some(port)) => Some(port.clone()),
+        (true, None) => None,
+    };
+    if let Some(port) = http_port {
+        let addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), port.parse()?);
+        let incoming = TcpListener::bind(&addr).await?;
+        log::info!("starting {} on http://{}", params.name, addr);
+        listeners.spawn(listener::serve_plain(
+            incoming,
+            connection_limits.clone(),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    batchqueue::start(batching, inference_pool);
+    // the server starts without a model, /readyz and /inference report 503 until one is loaded
+    let model_load = tokio::spawn(modelstore::load_with_retry());
+    // only the listeners and their connections hold a receiver from here on
+    drop(shutdown_rx);
+
+    // listeners only return early on accept errors
+    tokio::select! {
+        signal = listener::shutdown_signal() => log::info!("{} received, shutting down", signal?),
+        Some(result) = listeners.join_next() => result??,
+    }
+    let started = Instant::now();
+    model_load.abort();
+    // stop receiving new traffic first, then stop accepting
+    modelstore::set_not_ready();
+    let delay = params.shutdown_delay.unwrap_or(5);
+    if delay > 0 {
+        log::info!(
+            "readyz reports not ready, waiting {}s before closing listeners",
+            delay
+        );
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+    }
+    let open = listener::open_connections();
+    let _ = shutdown_tx.send(true);
+    while listeners.join_next().await.is_some() {}
+    log::info!("listeners closed, draining {} open connections", open);
+    let timeout = params.shutdown_timeout.unwrap_or(30);
+    let drained = tokio::time::timeout(Duration::from_secs(timeout), shutdown_tx.closed())
+        .await
+        .is_ok();
+    let remaining = listener::open_connections();
+    log::info!(
+        "shutdown {} in {:.1}s : served {} connections, {} requests : drained {}, cut {}",
+        if drained { "complete" } else { "timed out" },
+        started.elapsed().as_secs_f64(),
+        listener::total_connections(),
+        server::request_count(),
+        open - remaining.min(open),
+        remaining
+    );
+    Ok(())
 }
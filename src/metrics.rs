@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+// seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+const BATCH_SIZE_BUCKETS: &[f64] = &[1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0];
+const CONFIDENCE_BUCKETS: &[f64] = &[0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 0.95, 0.99, 1.0];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+enum Series {
+    Value(f64),
+    Histogram {
+        bounds: &'static [f64],
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    // keyed by the rendered label set, e.g. route="/inference",status="200"
+    series: BTreeMap<String, Series>,
+}
+
+static REGISTRY: Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+static IN_FLIGHT: AtomicI64 = AtomicI64::new(0);
+
+/// Decrements the in flight gauge when the request is done
+pub struct InFlight;
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn request_started() -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    InFlight
+}
+
+pub fn observe_request(route: &str, status: u16, seconds: f64) {
+    let status = status.to_string();
+    let labels = [("route", route), ("status", status.as_str())];
+    inc_counter(
+        "queuemetrics_http_requests_total",
+        "HTTP requests by route and status",
+        &labels,
+        1.0,
+    );
+    observe(
+        "queuemetrics_http_request_duration_seconds",
+        "HTTP request latency by route",
+        LATENCY_BUCKETS,
+        &[("route", route)],
+        seconds,
+    );
+}
+
+/// Requests rejected by the rate limiter, by route and what the client was keyed by
+pub fn inc_throttled(route: &str, key_by: &str) {
+    inc_counter(
+        "queuemetrics_http_throttled_total",
+        "Requests rejected with 429 by the rate limiter",
+        &[("route", route), ("key_by", key_by)],
+        1.0,
+    );
+}
+
+/// Inference latency per stage (parse, queue, batch, forward), batch and forward are per batch
+pub fn observe_stage(stage: &str, seconds: f64) {
+    observe(
+        "queuemetrics_inference_stage_duration_seconds",
+        "Inference latency split into parse, queue, batch and forward time",
+        LATENCY_BUCKETS,
+        &[("stage", stage)],
+        seconds,
+    );
+}
+
+/// Rows in each forward pass
+pub fn observe_batch_size(rows: usize) {
+    observe(
+        "queuemetrics_inference_batch_size",
+        "Rows per inference batch",
+        BATCH_SIZE_BUCKETS,
+        &[],
+        rows as f64,
+    );
+}
+
+/// The configured batching limits
+pub fn set_batch_config(max_batch_size: usize, max_wait_ms: u64) {
+    set_gauge(
+        "queuemetrics_inference_batch_max_size",
+        "Configured max rows per inference batch",
+        &[],
+        max_batch_size as f64,
+    );
+    set_gauge(
+        "queuemetrics_inference_batch_max_wait_seconds",
+        "Configured max time a request waits for its batch to fill",
+        &[],
+        max_wait_ms as f64 / 1000.0,
+    );
+}
+
+/// Requests waiting for an inference batch
+pub fn set_queue_depth(depth: usize) {
+    set_gauge(
+        "queuemetrics_inference_queue_depth",
+        "Requests waiting in the inference queue",
+        &[],
+        depth as f64,
+    );
+}
+
+/// Requests turned away before inference, by reason
+pub fn inc_rejected(reason: &str) {
+    inc_counter(
+        "queuemetrics_inference_rejected_total",
+        "Inference requests rejected before reaching the model",
+        &[("reason", reason)],
+        1.0,
+    );
+}
+
+/// Prediction log records dropped because the writer fell behind
+pub fn inc_prediction_log_dropped() {
+    inc_counter(
+        "queuemetrics_prediction_log_dropped_total",
+        "Predictions not written to the prediction log because its queue was full",
+        &[],
+        1.0,
+    );
+}
+
+/// Feedback received for served predictions, and the accuracy of the model version since startup
+pub fn observe_feedback(model_version: &str, correct: bool, accuracy: f64) {
+    inc_counter(
+        "queuemetrics_feedback_total",
+        "Predictions with a recorded true label, by model version and outcome",
+        &[
+            ("version", model_version),
+            ("correct", if correct { "true" } else { "false" }),
+        ],
+        1.0,
+    );
+    set_gauge(
+        "queuemetrics_live_accuracy",
+        "Share of predictions with feedback that were correct, by model version",
+        &[("version", model_version)],
+        accuracy,
+    );
+}
+
+/// Drift scores of a feature over the recent requests
+pub fn set_feature_drift(feature: &str, psi: f64, ks: Option<f64>, out_of_range: Option<f64>) {
+    set_gauge(
+        "queuemetrics_feature_drift_psi",
+        "Population stability index of recent requests against the training split",
+        &[("feature", feature)],
+        psi,
+    );
+    if let Some(ks) = ks {
+        set_gauge(
+            "queuemetrics_feature_drift_ks",
+            "Largest cdf gap of recent requests to the training split at the training quantile bin edges (binned Kolmogorov-Smirnov distance)",
+            &[("feature", feature)],
+            ks,
+        );
+    }
+    if let Some(out_of_range) = out_of_range {
+        set_gauge(
+            "queuemetrics_feature_out_of_range_ratio",
+            "Share of recent values outside the training min and max",
+            &[("feature", feature)],
+            out_of_range,
+        );
+    }
+}
+
+pub fn observe_prediction(class: &str, confidence: f64) {
+    inc_counter(
+        "queuemetrics_predictions_total",
+        "Predictions per class",
+        &[("class", class)],
+        1.0,
+    );
+    observe(
+        "queuemetrics_prediction_confidence",
+        "Softmax probability of the predicted class",
+        CONFIDENCE_BUCKETS,
+        &[],
+        confidence,
+    );
+}
+
+/// The loaded model version, exposed as an info gauge
+pub fn set_model_version(version: &str) {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        registry.remove("queuemetrics_model_info");
+    }
+    set_gauge(
+        "queuemetrics_model_info",
+        "Currently loaded model version",
+        &[("version", version)],
+        1.0,
+    );
+}
+
+pub fn inc_counter(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+    update(name, help, Kind::Counter, labels, |series| match series {
+        Some(Series::Value(v)) => Series::Value(v + value),
+        _ => Series::Value(value),
+    });
+}
+
+pub fn set_gauge(name: &'static str, help: &'static str, labels: &[(&str, &str)], value: f64) {
+    update(name, help, Kind::Gauge, labels, |_| Series::Value(value));
+}
+
+pub fn observe(
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    labels: &[(&str, &str)],
+    value: f64,
+) {
+    update(name, help, Kind::Histogram, labels, |series| {
+        let (mut buckets, sum, count) = match series {
+            Some(Series::Histogram {
+                buckets,
+                sum,
+                count,
+                ..
+            }) => (buckets, sum, count),
+            _ => (vec![0; bounds.len()], 0.0, 0),
+        };
+        for (bucket, bound) in buckets.iter_mut().zip(bounds.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        Series::Histogram {
+            bounds,
+            buckets,
+            sum: sum + value,
+            count: count + 1,
+        }
+    });
+}
+
+fn update(
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &[(&str, &str)],
+    f: impl FnOnce(Option<Series>) -> Series,
+) {
+    // metrics are best effort, a poisoned lock only stops recording
+    let mut registry = match REGISTRY.lock() {
+        Ok(registry) => registry,
+        Err(_) => return,
+    };
+    let family = registry.entry(name).or_insert_with(|| Family {
+        help,
+        kind,
+        series: BTreeMap::new(),
+    });
+    let key = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>()
+        .join(",");
+    let current = family.series.remove(&key);
+    family.series.insert(key, f(current));
+}
+
+/// Renders all metrics in the prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    let in_flight = IN_FLIGHT.load(Ordering::Relaxed);
+    let _ = writeln!(
+        out,
+        "# HELP queuemetrics_http_requests_in_flight Requests being served\n\
+         # TYPE queuemetrics_http_requests_in_flight gauge\n\
+         queuemetrics_http_requests_in_flight {}",
+        in_flight
+    );
+    let registry = match REGISTRY.lock() {
+        Ok(registry) => registry,
+        Err(_) => return out,
+    };
+    for (name, family) in registry.iter() {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, series) in family.series.iter() {
+            match series {
+                Series::Value(value) => {
+                    let _ = writeln!(out, "{}{} {}", name, braces(labels), value);
+                }
+                Series::Histogram {
+                    bounds,
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    let sep = if labels.is_empty() { "" } else { "," };
+                    for (bound, bucket) in bounds.iter().zip(buckets.iter()) {
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{{{}{}le=\"{}\"}} {}",
+                            name, labels, sep, bound, bucket
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                        name, labels, sep, count
+                    );
+                    let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), sum);
+                    let _ = writeln!(out, "{}_count{} {}", name, braces(labels), count);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", labels)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,19 +1,31 @@
 use crate::dataset::QueueMetricsBatch;
 use burn::{
-    nn::{loss::CrossEntropyLossConfig, Dropout, DropoutConfig, Linear, LinearConfig, Relu},
+    nn::{
+        loss::CrossEntropyLossConfig, Dropout, DropoutConfig, Embedding, EmbeddingConfig, Linear,
+        LinearConfig, Relu,
+    },
     prelude::*,
     tensor::backend::AutodiffBackend,
     train::{ClassificationOutput, TrainOutput, TrainStep, ValidStep},
 };
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Module, Debug)]
 pub struct Model<B: Backend> {
+    embeddings: Vec<Embedding<B>>,
     input_layer: Linear<B>,
     output_layer: Linear<B>,
     activation: Relu,
     dropout: Dropout,
 }
 
+/// Embedding table for an index encoded categorical feature
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmbeddingSize {
+    pub categories: usize,
+    pub dim: usize,
+}
+
 #[derive(Config)]
 pub struct ModelConfig {
     pub input_size: usize,
@@ -22,11 +34,21 @@ pub struct ModelConfig {
 
     #[config(default = "0.5")]
     pub dropout: f64,
+
+    /// one per categorical feature, in schema order, the embeddings are appended to the inputs
+    #[config(default = "Vec::new()")]
+    pub embeddings: Vec<EmbeddingSize>,
 }
 
 impl ModelConfig {
     pub fn init<B: Backend>(&self, device: &B::Device) -> Model<B> {
-        let input_layer = LinearConfig::new(self.input_size, self.hidden_size)
+        let embeddings = self
+            .embeddings
+            .iter()
+            .map(|e| EmbeddingConfig::new(e.categories, e.dim).init(device))
+            .collect::<Vec<_>>();
+        let input_size = self.input_size + self.embeddings.iter().map(|e| e.dim).sum::<usize>();
+        let input_layer = LinearConfig::new(input_size, self.hidden_size)
             .with_bias(true)
             .init(device);
         let output_layer = LinearConfig::new(self.hidden_size, self.classes)
@@ -35,6 +57,7 @@ impl ModelConfig {
         let d = DropoutConfig::new(self.dropout).init();
 
         Model {
+            embeddings,
             input_layer,
             output_layer,
             activation: Relu::new(),
@@ -44,21 +67,42 @@ impl ModelConfig {
 }
 
 impl<B: Backend> Model<B> {
-    pub fn forward(&self, input: Tensor<B, 2>) -> Tensor<B, 2> {
-        let x = self.input_layer.forward(input);
+    /// categories holds one index column per embedding table, it is required when the model has embeddings
+    pub fn forward(
+        &self,
+        input: Tensor<B, 2>,
+        categories: Option<Tensor<B, 2, Int>>,
+    ) -> Tensor<B, 2> {
+        let x = match categories {
+            Some(categories) if !self.embeddings.is_empty() => {
+                let [batch_size, _] = input.dims();
+                let mut inputs = vec![input];
+                for (i, embedding) in self.embeddings.iter().enumerate() {
+                    let column = categories.clone().slice([0..batch_size, i..i + 1]);
+                    let x = embedding.forward(column);
+                    let [_, _, dim] = x.dims();
+                    inputs.push(x.reshape([batch_size, dim]));
+                }
+                Tensor::cat(inputs, 1)
+            }
+            None if !self.embeddings.is_empty() => panic!(
+                "model has {} embedding tables but the batch has no category indices, \
+                 the batch should be built with the model's schema",
+                self.embeddings.len()
+            ),
+            _ => input,
+        };
+        let x = self.input_layer.forward(x);
         let x = self.dropout.forward(x);
         let x = self.activation.forward(x);
         self.output_layer.forward(x)
         //softmax(x, 1)
     }
 
-    pub fn forward_classification(
-        &self,
-        inputs: Tensor<B, 2>,
-        targets: Tensor<B, 1, Int>,
-    ) -> ClassificationOutput<B> {
-        let output = self.forward(inputs.clone());
-        let loss = CrossEntropyLossConfig::new().init(&inputs.device());
+    pub fn forward_classification(&self, item: QueueMetricsBatch<B>) -> ClassificationOutput<B> {
+        let targets = item.targets;
+        let output = self.forward(item.inputs, item.categories);
+        let loss = CrossEntropyLossConfig::new().init(&output.device());
         let loss = loss.forward(output.clone(), targets.clone());
 
         ClassificationOutput {
@@ -71,13 +115,13 @@ impl<B: Backend> Model<B> {
 
 impl<B: AutodiffBackend> TrainStep<QueueMetricsBatch<B>, ClassificationOutput<B>> for Model<B> {
     fn step(&self, item: QueueMetricsBatch<B>) -> TrainOutput<ClassificationOutput<B>> {
-        let item = self.forward_classification(item.inputs, item.targets);
+        let item = self.forward_classification(item);
         TrainOutput::new(self, item.loss.backward(), item)
     }
 }
 
 impl<B: Backend> ValidStep<QueueMetricsBatch<B>, ClassificationOutput<B>> for Model<B> {
     fn step(&self, item: QueueMetricsBatch<B>) -> ClassificationOutput<B> {
-        self.forward_classification(item.inputs, item.targets)
+        self.forward_classification(item)
     }
 }
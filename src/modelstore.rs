@@ -0,0 +1,185 @@
+use burn::{
+    config::Config,
+    data::dataloader::batcher::Batcher,
+    module::Module,
+    record::{CompactRecorder, Recorder},
+};
+use burn_cuda::{Cuda, CudaDevice};
+
+use crate::{
+    dataset::QueueMetricsBatcher,
+    drift::{self, TrainingStats},
+    metrics,
+    model::{Model, ModelRecord},
+    schema::FeatureSchema,
+    serviceerror::ServiceError,
+    training::{ExpConfig, ValidMetrics},
+    MAP_LOOKUP,
+};
+
+use custom_logger as log;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+pub type MyBackend = Cuda<f32, i32>;
+
+/// The served model, loaded once from the artifacts dir
+pub struct LoadedModel {
+    // burn modules are Send but not Sync, callers take a (cheap) clone
+    model: Mutex<Model<MyBackend>>,
+    pub batcher: QueueMetricsBatcher<MyBackend>,
+    pub device: CudaDevice,
+    pub config: ExpConfig,
+    /// shared with the prediction log records
+    pub schema: Arc<FeatureSchema>,
+    /// content hash of the model file
+    pub version: String,
+    /// validation metrics saved at train time (metrics.json)
+    pub metrics: Option<ValidMetrics>,
+    /// feature distributions of the training split (training-stats.json)
+    pub training_stats: Option<TrainingStats>,
+}
+
+impl LoadedModel {
+    pub fn model(&self) -> Result<Model<MyBackend>, ServiceError> {
+        self.model
+            .lock()
+            .map(|model| model.clone())
+            .map_err(|_| ServiceError::Internal("model lock is poisoned".to_string()))
+    }
+}
+
+static MODEL: RwLock<Option<Arc<LoadedModel>>> = RwLock::new(None);
+// set once the model is loaded and a warm up forward pass succeeded
+static READY: AtomicBool = AtomicBool::new(false);
+// set when shutting down, a load that finishes afterwards doesn't report ready again
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+// delay before the first load retry, doubled after each failure
+const LOAD_RETRY_MIN: Duration = Duration::from_secs(1);
+const LOAD_RETRY_MAX: Duration = Duration::from_secs(60);
+
+pub fn artifact_dir() -> Result<String, ServiceError> {
+    let hm = MAP_LOOKUP
+        .lock()
+        .map_err(|_| ServiceError::Internal("lookup map lock is poisoned".to_string()))?
+        .clone();
+    hm.and_then(|hm| hm.get("artifact_dir").cloned())
+        .ok_or(ServiceError::Internal(
+            "artifact_dir is not set".to_string(),
+        ))
+}
+
+/// Loads the model from the artifacts dir, runs a warm up forward pass and makes it the served model
+pub fn load() -> Result<Arc<LoadedModel>, ServiceError> {
+    let artifact_dir = artifact_dir()?;
+    let device = CudaDevice::default();
+    let config = ExpConfig::load(format!("{artifact_dir}/config.json")).map_err(|e| {
+        ServiceError::Unavailable(format!("model config not loaded ({}); run train first", e))
+    })?;
+    let record: ModelRecord<MyBackend> = CompactRecorder::new()
+        .load(format!("{artifact_dir}/model").into(), &device)
+        .map_err(|e| {
+            ServiceError::Unavailable(format!("model not loaded ({}); run train first", e))
+        })?;
+    let schema = FeatureSchema::read_artifacts(&artifact_dir).map_err(|e| {
+        ServiceError::Unavailable(format!("schema not loaded ({}); run train first", e))
+    })?;
+    let model = config.model.init(&device).load_record(record);
+    let batcher = QueueMetricsBatcher::new(device.clone(), &schema);
+    let version = std::fs::read(format!("{artifact_dir}/model.mpk"))
+        .map(|data| fnv1a(&data))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let metrics = std::fs::read(format!("{artifact_dir}/metrics.json"))
+        .ok()
+        .and_then(|data| serde_json::from_slice::<ValidMetrics>(&data).ok());
+    let training_stats = TrainingStats::read_artifacts(&artifact_dir);
+
+    let loaded = Arc::new(LoadedModel {
+        model: Mutex::new(model),
+        batcher,
+        device,
+        config,
+        schema: Arc::new(schema),
+        version,
+        metrics,
+        training_stats,
+    });
+    warm_up(&loaded)?;
+    *MODEL
+        .write()
+        .map_err(|_| ServiceError::Internal("model lock is poisoned".to_string()))? =
+        Some(loaded.clone());
+    READY.store(!STOPPING.load(Ordering::SeqCst), Ordering::SeqCst);
+    metrics::set_model_version(&loaded.version);
+    drift::set_training_stats(loaded.training_stats.clone());
+    log::info!("model {} loaded from {}", loaded.version, artifact_dir);
+    Ok(loaded)
+}
+
+/// Loads the model off the runtime threads, retrying with a doubling delay (1s up to 60s) until it
+/// succeeds, /readyz and /inference report 503 meanwhile
+pub async fn load_with_retry() {
+    let mut delay = LOAD_RETRY_MIN;
+    loop {
+        let error = match tokio::task::spawn_blocking(load).await {
+            Ok(Ok(_)) => return,
+            Ok(Err(e)) => e.to_string(),
+            Err(e) => format!("model load task failed: {}", e),
+        };
+        log::error!("{}, retrying in {}s", error, delay.as_secs());
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(LOAD_RETRY_MAX);
+    }
+}
+
+/// The served model, 503 until a model is loaded
+pub fn get() -> Result<Arc<LoadedModel>, ServiceError> {
+    let model = MODEL
+        .read()
+        .map_err(|_| ServiceError::Internal("model lock is poisoned".to_string()))?;
+    model
+        .clone()
+        .ok_or(ServiceError::Unavailable("model is not loaded".to_string()))
+}
+
+/// Reports not ready on /readyz, used when shutting down so load balancers stop sending traffic
+pub fn set_not_ready() {
+    STOPPING.store(true, Ordering::SeqCst);
+    READY.store(false, Ordering::SeqCst);
+}
+
+pub fn is_ready() -> bool {
+    READY.load(Ordering::SeqCst)
+}
+
+// one row through the model, checks the model, schema and device work together
+fn warm_up(loaded: &LoadedModel) -> Result<(), ServiceError> {
+    let batch = loaded
+        .batcher
+        .batch(vec![loaded.schema.sample()], &loaded.device);
+    let output = loaded.model()?.forward(batch.inputs, batch.categories);
+    let output = output
+        .into_data()
+        .into_vec::<f32>()
+        .map_err(|e| ServiceError::Unavailable(format!("warm up failed {:?}", e)))?;
+    if output.len() != loaded.schema.num_classes {
+        return Err(ServiceError::Unavailable(format!(
+            "warm up returned {} outputs, the schema has {} classes",
+            output.len(),
+            loaded.schema.num_classes
+        )));
+    }
+    Ok(())
+}
+
+// stable 64 bit content hash, used as the model version
+fn fnv1a(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
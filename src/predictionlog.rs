@@ -0,0 +1,768 @@
+use crate::certhandler::error;
+use crate::metrics;
+use crate::schema::{FeatureSchema, FeatureType};
+use crate::serverconfig::PredictionLog;
+use crate::serviceerror::ServiceError;
+
+use custom_logger as log;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const FORMATS: &[&str] = &["csv", "jsonl"];
+const FILE_PREFIX: &str = "predictions-";
+// records waiting for the writer thread, further records are dropped and counted
+const QUEUE_SIZE: usize = 4096;
+// recent predictions kept in memory so feedback rarely has to read the files
+const RECENT_SIZE: usize = 100_000;
+
+/// One served prediction
+pub struct PredictionRecord {
+    pub request_id: String,
+    pub timestamp: String,
+    /// version and schema of the model that served the prediction
+    pub model_version: String,
+    pub schema: Arc<FeatureSchema>,
+    /// raw feature values in schema order
+    pub features: Vec<f32>,
+    pub predicted: usize,
+    /// softmax probability per class
+    pub probabilities: Vec<f64>,
+}
+
+/// A logged prediction, found by request id, with the feature values as written in the training csv
+#[derive(Clone, Debug)]
+pub struct LoggedPrediction {
+    /// feature and label column names of the schema the prediction was served with
+    pub columns: Vec<String>,
+    pub label: String,
+    pub features: Vec<String>,
+    pub model_version: String,
+    pub predicted: usize,
+}
+
+struct Recent {
+    by_id: BTreeMap<String, LoggedPrediction>,
+    order: VecDeque<String>,
+}
+
+static QUEUE: OnceLock<SyncSender<PredictionRecord>> = OnceLock::new();
+static CONFIG: OnceLock<PredictionLog> = OnceLock::new();
+static RECENT: Mutex<Recent> = Mutex::new(Recent {
+    by_id: BTreeMap::new(),
+    order: VecDeque::new(),
+});
+
+/// Starts the writer thread, fails on an unknown format or a dir that can't be created
+pub fn init(config: PredictionLog) -> io::Result<()> {
+    if !FORMATS.contains(&config.format.as_str()) {
+        return Err(error(format!(
+            "prediction_log format must be one of {}, found {}",
+            FORMATS.join(", "),
+            config.format
+        )));
+    }
+    fs::create_dir_all(&config.dir)
+        .map_err(|e| error(format!("failed to create {}: {}", config.dir, e)))?;
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    if QUEUE.set(tx).is_err() {
+        return Ok(());
+    }
+    let _ = CONFIG.set(config.clone());
+    log::info!(
+        "prediction log : {} files in {} : max file size {} : max files {}",
+        config.format,
+        config.dir,
+        config.max_file_size,
+        config.max_files
+    );
+    std::thread::Builder::new()
+        .name("prediction-log".to_string())
+        .spawn(move || run(rx, Writer::new(config)))?;
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    QUEUE.get().is_some()
+}
+
+/// Queues the record for the writer thread, never blocks the request
+pub fn write(record: PredictionRecord) {
+    let Some(queue) = QUEUE.get() else {
+        return;
+    };
+    let schema = &record.schema;
+    let logged = LoggedPrediction {
+        columns: schema.features.iter().map(|f| f.name.clone()).collect(),
+        label: schema.label.clone(),
+        features: schema
+            .features
+            .iter()
+            .zip(record.features.iter())
+            .map(|(feature, value)| feature.csv_value(*value))
+            .collect(),
+        model_version: record.model_version.clone(),
+        predicted: record.predicted,
+    };
+    // a client supplied id seen since the check in check_unused, the first prediction is kept
+    if !remember(record.request_id.clone(), logged) {
+        log::warn!(
+            "prediction log : request id {} is already logged, the prediction isn't logged",
+            record.request_id
+        );
+        return;
+    }
+    match queue.try_send(record) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => metrics::inc_prediction_log_dropped(),
+        Err(TrySendError::Disconnected(_)) => {}
+    }
+}
+
+/// The directory the prediction log is written to, None when it's not configured
+pub fn dir() -> Option<String> {
+    CONFIG.get().map(|config| config.dir.clone())
+}
+
+/// 409 when a recent prediction was logged with the request id, so a client supplied (or guessed)
+/// X-Request-Id can't take over another prediction's feedback
+pub fn check_unused(request_id: &str) -> Result<(), ServiceError> {
+    if !enabled() {
+        return Ok(());
+    }
+    let known = recent()
+        .map_err(|e| ServiceError::Internal(e.to_string()))?
+        .by_id
+        .contains_key(request_id);
+    if known {
+        return Err(ServiceError::Conflict(format!(
+            "request id {} is already used by a logged prediction",
+            request_id
+        )));
+    }
+    Ok(())
+}
+
+/// Looks the prediction up in memory, then in the log files newest first (blocking)
+pub fn find(request_id: &str, schema: &FeatureSchema) -> io::Result<Option<LoggedPrediction>> {
+    let Some(config) = CONFIG.get() else {
+        return Ok(None);
+    };
+    if let Some(logged) = recent()?.by_id.get(request_id) {
+        return Ok(Some(logged.clone()));
+    }
+    let found = find_in_files(&config.dir, request_id, schema)?;
+    if let Some(logged) = &found {
+        remember(request_id.to_string(), logged.clone());
+    }
+    Ok(found)
+}
+
+// the newest row with the request id in the log files of dir
+fn find_in_files(
+    dir: &str,
+    request_id: &str,
+    schema: &FeatureSchema,
+) -> io::Result<Option<LoggedPrediction>> {
+    for path in log_files(dir)?.iter().rev() {
+        let found = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => find_csv(path, request_id)?,
+            Some("jsonl") => find_jsonl(path, request_id, schema)?,
+            _ => None,
+        };
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+fn recent() -> io::Result<std::sync::MutexGuard<'static, Recent>> {
+    RECENT
+        .lock()
+        .map_err(|_| error("prediction log lock is poisoned".to_string()))
+}
+
+// keeps the prediction unless one is already known for the request id, false then
+fn remember(request_id: String, logged: LoggedPrediction) -> bool {
+    let Ok(mut recent) = recent() else {
+        return false;
+    };
+    if recent.by_id.contains_key(&request_id) {
+        return false;
+    }
+    recent.by_id.insert(request_id.clone(), logged);
+    recent.order.push_back(request_id);
+    while recent.order.len() > RECENT_SIZE {
+        if let Some(oldest) = recent.order.pop_front() {
+            recent.by_id.remove(&oldest);
+        }
+    }
+    true
+}
+
+// the file header has the features and the label of the schema it was written with, before the
+// request_id column
+fn find_csv(path: &Path, request_id: &str) -> io::Result<Option<LoggedPrediction>> {
+    let mut rdr = csv::Reader::from_path(path)?;
+    let header: Vec<String> = rdr.headers()?.iter().map(String::from).collect();
+    let headers: HashMap<String, usize> = header
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (name.clone(), i))
+        .collect();
+    let (columns, label) = match headers.get("request_id") {
+        Some(index) if *index >= 2 => (header[..index - 1].to_vec(), header[index - 1].clone()),
+        _ => {
+            return Err(error(format!(
+                "{} has no feature columns before request_id",
+                path.display()
+            )))
+        }
+    };
+    let column = |record: &csv::StringRecord, name: &str| -> String {
+        headers
+            .get(name)
+            .and_then(|i| record.get(*i))
+            .unwrap_or("")
+            .to_string()
+    };
+    for record in rdr.records() {
+        let record = record?;
+        if column(&record, "request_id") != request_id {
+            continue;
+        }
+        return Ok(Some(LoggedPrediction {
+            features: columns.iter().map(|name| column(&record, name)).collect(),
+            columns,
+            label,
+            model_version: column(&record, "model_version"),
+            predicted: parse_predicted(path, request_id, &column(&record, "predicted"))?,
+        }));
+    }
+    Ok(None)
+}
+
+fn find_jsonl(
+    path: &Path,
+    request_id: &str,
+    schema: &FeatureSchema,
+) -> io::Result<Option<LoggedPrediction>> {
+    let reader = BufReader::new(File::open(path)?);
+    for line in reader.lines() {
+        let line = line?;
+        // cheap check before parsing the line
+        if !line.contains(request_id) {
+            continue;
+        }
+        let Ok(Value::Object(object)) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if object.get("request_id").and_then(Value::as_str) != Some(request_id) {
+            continue;
+        }
+        // numbers were written from f32, formatted back the way csv_value writes them
+        let text = |name: &str| match object.get(name) {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Number(value)) => value
+                .as_f64()
+                .map(|value| (value as f32).to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        // json objects don't keep the column order, the features are read by the current schema
+        if let Some(feature) = schema
+            .features
+            .iter()
+            .find(|f| !object.contains_key(&f.name))
+        {
+            return Err(error(format!(
+                "{}: request {} was logged without feature {}, with another schema",
+                path.display(),
+                request_id,
+                feature.name
+            )));
+        }
+        return Ok(Some(LoggedPrediction {
+            columns: schema.features.iter().map(|f| f.name.clone()).collect(),
+            label: schema.label.clone(),
+            features: schema.features.iter().map(|f| text(&f.name)).collect(),
+            model_version: text("model_version"),
+            predicted: parse_predicted(path, request_id, &text("predicted"))?,
+        }));
+    }
+    Ok(None)
+}
+
+fn parse_predicted(path: &Path, request_id: &str, value: &str) -> io::Result<usize> {
+    value.parse().map_err(|_| {
+        error(format!(
+            "{}: request {} has an invalid predicted class {:?}",
+            path.display(),
+            request_id,
+            value
+        ))
+    })
+}
+
+// prediction log files in dir, oldest first
+fn log_files(dir: &str) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(FILE_PREFIX))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// writes records as they come, flushing once the queue is drained
+fn run(rx: Receiver<PredictionRecord>, mut writer: Writer) {
+    while let Ok(record) = rx.recv() {
+        writer.write_logged(&record);
+        while let Ok(record) = rx.try_recv() {
+            writer.write_logged(&record);
+        }
+        if let Err(err) = writer.flush() {
+            log::error!("prediction log : {}", err);
+        }
+    }
+}
+
+struct OpenFile {
+    out: BufWriter<File>,
+    path: PathBuf,
+    size: u64,
+    // the model the file was started for, its csv header follows that model's schema
+    model_version: String,
+}
+
+struct Writer {
+    config: PredictionLog,
+    file: Option<OpenFile>,
+    // (millis, sequence) of the last file started, names only ever increase so the name of a
+    // pruned file isn't taken again
+    last_name: Option<(u128, u32)>,
+}
+
+impl Writer {
+    fn new(config: PredictionLog) -> Self {
+        Writer {
+            config,
+            file: None,
+            last_name: None,
+        }
+    }
+
+    // the file name after the last one, the sequence keeps to 4 digits so names sort by age
+    fn next_name(&self, millis: u128) -> (u128, u32) {
+        match self.last_name {
+            Some((last, sequence)) if millis <= last && sequence < 9999 => (last, sequence + 1),
+            Some((last, _)) if millis <= last => (last + 1, 0),
+            _ => (millis, 0),
+        }
+    }
+
+    fn write_logged(&mut self, record: &PredictionRecord) {
+        if let Err(err) = self.write(record) {
+            log::error!("prediction log : request {} : {}", record.request_id, err);
+        }
+    }
+
+    fn write(&mut self, record: &PredictionRecord) -> io::Result<()> {
+        let line = match self.config.format.as_str() {
+            "jsonl" => json_line(record)?,
+            _ => csv_line(&csv_row(record))?,
+        };
+        let rotate = match &self.file {
+            Some(file) => {
+                file.size + line.len() as u64 > self.config.max_file_size
+                    || file.model_version != record.model_version
+            }
+            None => true,
+        };
+        if rotate {
+            self.rotate(record)?;
+        }
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| error("no prediction log file open".to_string()))?;
+        file.out.write_all(&line)?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // starts a new file (with the header row for csv) and removes the oldest beyond max_files.
+    // Files are never reopened, rotations within the same millisecond take the next sequence number
+    fn rotate(&mut self, record: &PredictionRecord) -> io::Result<()> {
+        self.flush()?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let (file, path) = loop {
+            let (millis, sequence) = self.next_name(millis);
+            self.last_name = Some((millis, sequence));
+            let path = Path::new(&self.config.dir).join(format!(
+                "{}{:013}-{:04}.{}",
+                FILE_PREFIX, millis, sequence, self.config.format
+            ));
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(file) => break (file, path),
+                // left by an earlier run within the same millisecond, try the next name
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(error(format!("failed to open {}: {}", path.display(), e))),
+            }
+        };
+        let mut open = OpenFile {
+            out: BufWriter::new(file),
+            path,
+            size: 0,
+            model_version: record.model_version.clone(),
+        };
+        if self.config.format == "csv" {
+            let header = csv_line(&csv_header(record))?;
+            open.out.write_all(&header)?;
+            open.size += header.len() as u64;
+        }
+        log::debug!("prediction log : writing {}", open.path.display());
+        self.file = Some(open);
+        self.prune()
+    }
+
+    fn prune(&self) -> io::Result<()> {
+        if self.config.max_files == 0 {
+            return Ok(());
+        }
+        let files = log_files(&self.config.dir)?;
+        let excess = files.len().saturating_sub(self.config.max_files);
+        for path in files.iter().take(excess) {
+            fs::remove_file(path)
+                .map_err(|e| error(format!("failed to remove {}: {}", path.display(), e)))?;
+        }
+        Ok(())
+    }
+}
+
+// the training csv columns (features then the label, left empty until the row is labeled),
+// followed by the prediction details
+fn csv_header(record: &PredictionRecord) -> Vec<String> {
+    let schema = &record.schema;
+    let mut header: Vec<String> = schema.features.iter().map(|f| f.name.clone()).collect();
+    header.push(schema.label.clone());
+    header.extend(
+        [
+            "request_id",
+            "timestamp",
+            "model_version",
+            "predicted",
+            "predicted_class",
+        ]
+        .map(String::from),
+    );
+    header.extend(
+        schema
+            .class_names()
+            .iter()
+            .map(|class| format!("probability_{}", class)),
+    );
+    header
+}
+
+fn csv_row(record: &PredictionRecord) -> Vec<String> {
+    let schema = &record.schema;
+    let mut row: Vec<String> = schema
+        .features
+        .iter()
+        .zip(record.features.iter())
+        .map(|(feature, value)| feature.csv_value(*value))
+        .collect();
+    row.push(String::new());
+    row.push(record.request_id.clone());
+    row.push(record.timestamp.clone());
+    row.push(record.model_version.clone());
+    row.push(record.predicted.to_string());
+    row.push(class_name(record));
+    row.extend(record.probabilities.iter().map(|p| p.to_string()));
+    row
+}
+
+fn csv_line(fields: &[String]) -> io::Result<Vec<u8>> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.write_record(fields)?;
+    writer
+        .into_inner()
+        .map_err(|e| error(format!("failed to write csv row: {}", e)))
+}
+
+// the features by name as in a /inference request body, so the rows can be parsed with the schema
+fn json_line(record: &PredictionRecord) -> io::Result<Vec<u8>> {
+    let schema = &record.schema;
+    let mut object = Map::new();
+    for (feature, value) in schema.features.iter().zip(record.features.iter()) {
+        let value = match feature.feature_type {
+            FeatureType::Categorical => Value::from(feature.csv_value(*value)),
+            _ => Value::from(*value as f64),
+        };
+        object.insert(feature.name.clone(), value);
+    }
+    object.insert(schema.label.clone(), Value::Null);
+    object.insert(
+        "request_id".to_string(),
+        Value::from(record.request_id.clone()),
+    );
+    object.insert(
+        "timestamp".to_string(),
+        Value::from(record.timestamp.clone()),
+    );
+    object.insert(
+        "model_version".to_string(),
+        Value::from(record.model_version.clone()),
+    );
+    object.insert("predicted".to_string(), Value::from(record.predicted));
+    object.insert(
+        "predicted_class".to_string(),
+        Value::from(class_name(record)),
+    );
+    let probabilities: Map<String, Value> = schema
+        .class_names()
+        .into_iter()
+        .zip(record.probabilities.iter())
+        .map(|(class, p)| (class, Value::from(*p)))
+        .collect();
+    object.insert("probabilities".to_string(), Value::Object(probabilities));
+    let mut line = serde_json::to_vec(&object)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn class_name(record: &PredictionRecord) -> String {
+    record
+        .schema
+        .class_names()
+        .get(record.predicted)
+        .cloned()
+        .unwrap_or_else(|| record.predicted.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // a prediction log dir removed with the test
+    struct TempDir(String);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir()
+                .join(format!("queuemetrics-{}-{}", name, std::process::id()))
+                .to_string_lossy()
+                .to_string();
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn schema() -> Arc<FeatureSchema> {
+        Arc::new(
+            serde_json::from_value(json!({
+                "features": [
+                    { "name": "processors", "type": "int" },
+                    { "name": "queue_length" },
+                    { "name": "region", "type": "categorical", "categories": ["eu", "us"] }
+                ],
+                "label": "status",
+                "num_classes": 3,
+                "class_names": ["ok", "slow", "down"]
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn writer(dir: &TempDir, format: &str, max_file_size: u64, max_files: usize) -> Writer {
+        Writer::new(
+            serde_json::from_value(json!({
+                "dir": dir.0,
+                "format": format,
+                "max_file_size": max_file_size,
+                "max_files": max_files
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn record(request_id: &str, model_version: &str) -> PredictionRecord {
+        PredictionRecord {
+            request_id: request_id.to_string(),
+            timestamp: "2026-10-19T09:12:03.51Z".to_string(),
+            model_version: model_version.to_string(),
+            schema: schema(),
+            features: vec![3.0, 12.5, 1.0],
+            predicted: 1,
+            probabilities: vec![0.25, 0.5, 0.25],
+        }
+    }
+
+    fn write(writer: &mut Writer, records: &[PredictionRecord]) -> Vec<PathBuf> {
+        for record in records.iter() {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        log_files(&writer.config.dir).unwrap()
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn writes_csv_rows_with_the_training_columns() {
+        let dir = TempDir::new("plog-csv");
+        let files = write(&mut writer(&dir, "csv", 1 << 20, 0), &[record("r1", "v1")]);
+        assert_eq!(files.len(), 1);
+        let name = files[0].file_name().unwrap().to_str().unwrap();
+        assert!(
+            name.starts_with("predictions-") && name.ends_with("-0000.csv"),
+            "{name}"
+        );
+        assert_eq!(
+            lines(&files[0]),
+            vec![
+                "processors,queue_length,region,status,request_id,timestamp,model_version,predicted,predicted_class,probability_ok,probability_slow,probability_down",
+                "3,12.5,us,,r1,2026-10-19T09:12:03.51Z,v1,1,slow,0.25,0.5,0.25",
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_jsonl_lines_with_features_by_name() {
+        let dir = TempDir::new("plog-jsonl");
+        let files = write(
+            &mut writer(&dir, "jsonl", 1 << 20, 0),
+            &[record("r1", "v1")],
+        );
+        let lines = lines(&files[0]);
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "processors": 3.0, "queue_length": 12.5, "region": "us", "status": null,
+                "request_id": "r1", "timestamp": "2026-10-19T09:12:03.51Z", "model_version": "v1",
+                "predicted": 1, "predicted_class": "slow",
+                "probabilities": { "ok": 0.25, "slow": 0.5, "down": 0.25 }
+            })
+        );
+    }
+
+    #[test]
+    fn rotates_on_size_and_model_version() {
+        let dir = TempDir::new("plog-rotate");
+        // room for the header and two rows
+        let mut writer = writer(&dir, "csv", 300, 0);
+        let records: Vec<PredictionRecord> = (0..5)
+            .map(|i| record(&format!("r{}", i), if i < 4 { "v1" } else { "v2" }))
+            .collect();
+        let files = write(&mut writer, &records);
+        // r0 r1 | r2 r3 | r4 (new version), rotations in the same millisecond get their own file
+        let rows: Vec<usize> = files.iter().map(|path| lines(path).len() - 1).collect();
+        assert_eq!(rows, vec![2, 2, 1]);
+        for path in files.iter() {
+            assert!(lines(path)[0].starts_with("processors,"));
+        }
+        assert!(lines(&files[2])[1].contains(",r4,"));
+        assert!(files
+            .iter()
+            .all(|path| fs::metadata(path).unwrap().len() <= 300));
+    }
+
+    #[test]
+    fn removes_the_oldest_files_beyond_max_files() {
+        let dir = TempDir::new("plog-prune");
+        let mut writer = writer(&dir, "csv", 1 << 20, 2);
+        let records: Vec<PredictionRecord> = (0..4)
+            .map(|i| record(&format!("r{}", i), &format!("v{}", i)))
+            .collect();
+        let files = write(&mut writer, &records);
+        assert_eq!(files.len(), 2);
+        assert!(lines(&files[0])[1].contains(",r2,"));
+        assert!(lines(&files[1])[1].contains(",r3,"));
+    }
+
+    #[test]
+    fn finds_logged_predictions_in_the_files() {
+        for format in FORMATS {
+            let dir = TempDir::new(&format!("plog-find-{}", format));
+            let mut writer = writer(&dir, format, 1 << 20, 0);
+            write(&mut writer, &[record("r1", "v1"), record("r2", "v2")]);
+            let logged = find_in_files(&dir.0, "r1", &schema()).unwrap().unwrap();
+            assert_eq!(logged.columns, vec!["processors", "queue_length", "region"]);
+            assert_eq!(logged.label, "status");
+            assert_eq!(logged.features, vec!["3", "12.5", "us"]);
+            assert_eq!((logged.model_version.as_str(), logged.predicted), ("v1", 1));
+            assert!(find_in_files(&dir.0, "r3", &schema()).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn fails_on_a_row_that_cant_be_read_back() {
+        let dir = TempDir::new("plog-invalid");
+        write(&mut writer(&dir, "csv", 1 << 20, 0), &[record("r1", "v1")]);
+        let path = &log_files(&dir.0).unwrap()[0];
+        let content = fs::read_to_string(path)
+            .unwrap()
+            .replace(",v1,1,", ",v1,x,");
+        fs::write(path, content).unwrap();
+        let err = find_in_files(&dir.0, "r1", &schema()).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("request r1 has an invalid predicted class \"x\""));
+    }
+
+    #[test]
+    fn keeps_the_first_prediction_for_a_request_id() {
+        let logged = |model_version: &str| LoggedPrediction {
+            columns: Vec::new(),
+            label: "status".to_string(),
+            features: Vec::new(),
+            model_version: model_version.to_string(),
+            predicted: 0,
+        };
+        assert!(remember("plog-duplicate".to_string(), logged("v1")));
+        assert!(!remember("plog-duplicate".to_string(), logged("v2")));
+        assert_eq!(
+            recent().unwrap().by_id["plog-duplicate"].model_version,
+            "v1"
+        );
+    }
+}
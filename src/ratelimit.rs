@@ -0,0 +1,414 @@
+use crate::auth::Principal;
+use crate::certhandler::error;
+use crate::listener::ConnectionInfo;
+use crate::metrics;
+use crate::server;
+use crate::serverconfig::{RateLimit, RateLimits};
+use crate::serviceerror::ServiceError;
+
+use custom_logger as log;
+use http::Request;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const KEY_BY: &[&str] = &["auto", "ip", "api_key", "client_cert"];
+// full buckets are the same as no bucket, they are dropped this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+// when this many clients are tracked the least recently used tenth is dropped
+const MAX_TRACKED: usize = 100_000;
+// only limited by their own entry, never by *
+const PROBES: &[&str] = &["/healthz", "/readyz"];
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// (route, client key)
+type BucketKey = (&'static str, String);
+
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    swept: Instant,
+}
+
+struct RateLimiter {
+    key_by: String,
+    routes: HashMap<String, RateLimit>,
+    buckets: Mutex<Buckets>,
+}
+
+static LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// Turns rate limiting on, fails on an unknown key_by or a limit that lets nothing through
+pub fn init(config: RateLimits) -> io::Result<()> {
+    let limiter = RateLimiter::new(config)?;
+    for (route, limit) in limiter.routes.iter() {
+        log::info!(
+            "rate limit {} : {}/s : burst {} : by {}",
+            route,
+            limit.requests_per_second,
+            limit.burst,
+            limiter.key_by
+        );
+    }
+    let _ = LIMITER.set(limiter);
+    Ok(())
+}
+
+/// Takes a token from the client bucket for the route, 429 with the seconds until the next token
+/// when the bucket is empty
+pub fn check<B>(req: &Request<B>, route: &'static str) -> Result<(), ServiceError> {
+    match LIMITER.get() {
+        Some(limiter) => limiter.check(req, route),
+        None => Ok(()),
+    }
+}
+
+// drops the count buckets that were used the longest time ago
+fn evict_oldest(buckets: &mut HashMap<BucketKey, Bucket>, count: usize) {
+    if count == 0 {
+        return;
+    }
+    if buckets.len() <= count {
+        buckets.clear();
+        return;
+    }
+    let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+    let (_, cutoff, _) = updated.select_nth_unstable(count - 1);
+    let cutoff = *cutoff;
+    let mut dropped = 0;
+    buckets.retain(|_, bucket| {
+        if dropped < count && bucket.updated <= cutoff {
+            dropped += 1;
+            return false;
+        }
+        true
+    });
+}
+
+// tokens in the bucket at now, without updating it
+fn refill(bucket: &Bucket, limit: &RateLimit, now: Instant) -> f64 {
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * limit.requests_per_second).min(limit.burst as f64)
+}
+
+impl RateLimiter {
+    fn new(config: RateLimits) -> io::Result<RateLimiter> {
+        if !KEY_BY.contains(&config.key_by.as_str()) {
+            return Err(error(format!(
+                "rate_limits key_by must be one of {}, found {}",
+                KEY_BY.join(", "),
+                config.key_by
+            )));
+        }
+        for (route, limit) in config.routes.iter() {
+            if route != "*" && server::route_label(route) != route {
+                return Err(error(format!("rate limit for unknown route {}", route)));
+            }
+            if limit.requests_per_second <= 0.0 || limit.burst == 0 {
+                return Err(error(format!(
+                    "rate limit for {}: requests_per_second and burst must be above 0",
+                    route
+                )));
+            }
+        }
+        Ok(RateLimiter {
+            key_by: config.key_by,
+            routes: config.routes,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        })
+    }
+
+    // the route's own limit, or the * limit. Probes aren't covered by *, a throttled probe would
+    // restart or drain the instance
+    fn limit(&self, route: &str) -> Option<&RateLimit> {
+        match self.routes.get(route) {
+            Some(limit) => Some(limit),
+            None if PROBES.contains(&route) => None,
+            None => self.routes.get("*"),
+        }
+    }
+
+    fn check<B>(&self, req: &Request<B>, route: &'static str) -> Result<(), ServiceError> {
+        let Some(limit) = self.limit(route) else {
+            return Ok(());
+        };
+        let (source, key) = self.client_key(req);
+        let mut state = self
+            .buckets
+            .lock()
+            .map_err(|_| ServiceError::Internal("rate limit lock is poisoned".to_string()))?;
+        let now = Instant::now();
+        self.evict(&mut state, now);
+        let bucket = state.buckets.entry((route, key)).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        let tokens = refill(bucket, limit, now);
+        if tokens >= 1.0 {
+            bucket.tokens = tokens - 1.0;
+            bucket.updated = now;
+            return Ok(());
+        }
+        metrics::inc_throttled(route, source);
+        let retry_after = ((1.0 - tokens) / limit.requests_per_second).ceil().max(1.0);
+        Err(ServiceError::TooManyRequests(retry_after as u64))
+    }
+
+    // drops the buckets that refilled every SWEEP_INTERVAL, and the least recently used tenth when
+    // MAX_TRACKED clients are tracked, a dropped client starts again with a full bucket
+    fn evict(&self, state: &mut Buckets, now: Instant) {
+        if now.duration_since(state.swept) >= SWEEP_INTERVAL {
+            state
+                .buckets
+                .retain(|(route, _), bucket| match self.limit(route) {
+                    Some(limit) => refill(bucket, limit, now) < limit.burst as f64,
+                    None => false,
+                });
+            state.swept = now;
+        }
+        if state.buckets.len() >= MAX_TRACKED {
+            evict_oldest(&mut state.buckets, MAX_TRACKED / 10);
+        }
+    }
+
+    // the api key or token subject, the client certificate subject or the remote ip, by key_by,
+    // falling back to the ip when the request doesn't have the one asked for
+    fn client_key<B>(&self, req: &Request<B>) -> (&'static str, String) {
+        let principal = req.extensions().get::<Principal>();
+        let info = req.extensions().get::<ConnectionInfo>();
+        let client = info.and_then(|info| info.client.as_ref());
+        let by_key = || principal.map(|p| ("api_key", format!("key:{}", p.name)));
+        let by_cert = || client.map(|c| ("client_cert", format!("cert:{}", c.subject)));
+        let found = match self.key_by.as_str() {
+            "auto" => by_key().or_else(by_cert),
+            "api_key" => by_key(),
+            "client_cert" => by_cert(),
+            _ => None,
+        };
+        found.unwrap_or_else(|| {
+            let ip = info
+                .map(|info| info.remote_addr.ip().to_string())
+                .unwrap_or_default();
+            ("ip", format!("ip:{}", ip))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::SocketAddr;
+
+    fn limiter(config: serde_json::Value) -> RateLimiter {
+        RateLimiter::new(serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    fn request(ip: &str, principal: Option<&str>) -> Request<()> {
+        let mut req = Request::builder()
+            .extension(ConnectionInfo {
+                remote_addr: SocketAddr::new(ip.parse().unwrap(), 40000),
+                client: None,
+            })
+            .body(())
+            .unwrap();
+        if let Some(name) = principal {
+            req.extensions_mut().insert(Principal {
+                name: name.to_string(),
+                scopes: Vec::new(),
+            });
+        }
+        req
+    }
+
+    fn retry_after(result: Result<(), ServiceError>) -> Option<u64> {
+        match result {
+            Err(ServiceError::TooManyRequests(seconds)) => Some(seconds),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn refills_at_the_rate_up_to_the_burst() {
+        let limit = RateLimit {
+            requests_per_second: 4.0,
+            burst: 10,
+        };
+        let now = Instant::now();
+        let bucket = Bucket {
+            tokens: 0.5,
+            updated: now,
+        };
+        assert_eq!(
+            refill(&bucket, &limit, now + Duration::from_millis(500)),
+            2.5
+        );
+        assert_eq!(refill(&bucket, &limit, now + Duration::from_secs(60)), 10.0);
+    }
+
+    #[test]
+    fn throttles_an_empty_bucket_with_retry_after() {
+        let limiter = limiter(json!({
+            "routes": {
+                "/inference": { "requests_per_second": 0.25, "burst": 2 },
+                "/model": { "requests_per_second": 10, "burst": 1 }
+            }
+        }));
+        let req = request("10.0.0.1", None);
+        assert!(limiter.check(&req, "/inference").is_ok());
+        assert!(limiter.check(&req, "/inference").is_ok());
+        // a whole token at 0.25/s is 4 seconds away
+        assert_eq!(retry_after(limiter.check(&req, "/inference")), Some(4));
+        // fractions of a second round up to 1
+        assert!(limiter.check(&req, "/model").is_ok());
+        assert_eq!(retry_after(limiter.check(&req, "/model")), Some(1));
+    }
+
+    #[test]
+    fn keeps_a_bucket_per_client_and_route() {
+        let limiter = limiter(json!({
+            "routes": { "*": { "requests_per_second": 1, "burst": 1 } }
+        }));
+        assert!(limiter
+            .check(&request("10.0.0.1", None), "/inference")
+            .is_ok());
+        assert!(limiter
+            .check(&request("10.0.0.1", None), "/inference")
+            .is_err());
+        assert!(limiter
+            .check(&request("10.0.0.2", None), "/inference")
+            .is_ok());
+        assert!(limiter
+            .check(&request("10.0.0.1", None), "/feedback")
+            .is_ok());
+        // auto keys authenticated requests by api key, not ip
+        assert!(limiter
+            .check(&request("10.0.0.1", Some("scheduler")), "/inference")
+            .is_ok());
+    }
+
+    #[test]
+    fn leaves_probes_out_of_the_wildcard_limit() {
+        let wildcard = limiter(json!({
+            "routes": { "*": { "requests_per_second": 1, "burst": 1 } }
+        }));
+        let req = request("10.0.0.1", None);
+        for _ in 0..5 {
+            assert!(wildcard.check(&req, "/healthz").is_ok());
+            assert!(wildcard.check(&req, "/readyz").is_ok());
+        }
+        assert!(wildcard.check(&req, "/model").is_ok());
+        assert!(wildcard.check(&req, "/model").is_err());
+        // an explicit entry still applies
+        let explicit = limiter(json!({
+            "routes": { "/healthz": { "requests_per_second": 1, "burst": 1 } }
+        }));
+        assert!(explicit.check(&req, "/healthz").is_ok());
+        assert!(explicit.check(&req, "/healthz").is_err());
+    }
+
+    #[test]
+    fn leaves_routes_without_a_limit_alone() {
+        let limiter = limiter(json!({
+            "key_by": "ip",
+            "routes": { "/inference": { "requests_per_second": 1, "burst": 1 } }
+        }));
+        let req = request("10.0.0.1", Some("scheduler"));
+        for _ in 0..5 {
+            assert!(limiter.check(&req, "/model").is_ok());
+        }
+        assert!(limiter.check(&req, "/inference").is_ok());
+        // keyed by ip, another api key from the same address shares the bucket
+        assert!(limiter
+            .check(&request("10.0.0.1", Some("ops")), "/inference")
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        for (config, message) in [
+            (
+                json!({ "key_by": "header", "routes": {} }),
+                "rate_limits key_by must be one of auto, ip, api_key, client_cert, found header",
+            ),
+            (
+                json!({ "routes": { "/predict": { "requests_per_second": 1, "burst": 1 } } }),
+                "rate limit for unknown route /predict",
+            ),
+            (
+                json!({ "routes": { "*": { "requests_per_second": 0, "burst": 1 } } }),
+                "rate limit for *: requests_per_second and burst must be above 0",
+            ),
+        ] {
+            let err = RateLimiter::new(serde_json::from_value(config).unwrap())
+                .err()
+                .unwrap();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn sweeps_refilled_buckets_of_wildcard_routes() {
+        let limiter = limiter(json!({
+            "routes": { "*": { "requests_per_second": 1, "burst": 5 } }
+        }));
+        let now = Instant::now();
+        let mut state = limiter.buckets.lock().unwrap();
+        state.buckets.insert(
+            ("/inference", "ip:idle".to_string()),
+            Bucket {
+                tokens: 0.0,
+                updated: now - Duration::from_secs(10),
+            },
+        );
+        state.buckets.insert(
+            ("/inference", "ip:busy".to_string()),
+            Bucket {
+                tokens: 0.0,
+                updated: now,
+            },
+        );
+        // not yet due
+        limiter.evict(&mut state, now);
+        assert_eq!(state.buckets.len(), 2);
+        state.swept = now - SWEEP_INTERVAL;
+        limiter.evict(&mut state, now);
+        let keys: Vec<&String> = state.buckets.keys().map(|(_, key)| key).collect();
+        assert_eq!(keys, vec!["ip:busy"]);
+        assert_eq!(state.swept, now);
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_buckets() {
+        let now = Instant::now();
+        let mut buckets: HashMap<BucketKey, Bucket> = (0..10u64)
+            .map(|age| {
+                (
+                    ("/inference", format!("ip:{}", age)),
+                    Bucket {
+                        tokens: 0.0,
+                        updated: now - Duration::from_secs(age),
+                    },
+                )
+            })
+            .collect();
+        evict_oldest(&mut buckets, 3);
+        let mut kept: Vec<String> = buckets.keys().map(|(_, key)| key.clone()).collect();
+        kept.sort();
+        assert_eq!(
+            kept,
+            vec!["ip:0", "ip:1", "ip:2", "ip:3", "ip:4", "ip:5", "ip:6"]
+        );
+        evict_oldest(&mut buckets, 0);
+        assert_eq!(buckets.len(), 7);
+        evict_oldest(&mut buckets, 7);
+        assert!(buckets.is_empty());
+    }
+}
@@ -0,0 +1,896 @@
+use crate::dataset::QueueMetrics;
+use crate::model::EmbeddingSize;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+
+/// Feature schema, drives the dataset, batcher, model input size and the server json parsing.
+/// It is saved with the model (schema.json in the artifacts dir) so serving matches training.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FeatureSchema {
+    pub features: Vec<Feature>,
+    /// features computed from the numeric features, appended after them
+    #[serde(default)]
+    pub derived: Vec<DerivedFeature>,
+    /// label column (class index)
+    pub label: String,
+    pub num_classes: usize,
+    /// optional display name per class index
+    #[serde(default)]
+    pub class_names: Vec<String>,
+    /// default policy for missing or invalid values at inference time
+    #[serde(default)]
+    pub imputation: Imputation,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Feature {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub feature_type: FeatureType,
+    #[serde(default)]
+    pub normalization: Normalization,
+    /// categorical only, the value is the index in this list
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// categorical only
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// categorical only, embedding width when encoding is "embedding"
+    #[serde(default)]
+    pub embedding_dim: Option<usize>,
+    /// overrides the schema imputation policy
+    #[serde(default)]
+    pub imputation: Option<Imputation>,
+    /// values outside the range are rejected at inference time
+    #[serde(default)]
+    pub valid_range: Option<ValidRange>,
+    /// training split median (most frequent category for categorical), set at train time
+    #[serde(default)]
+    pub median: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum Imputation {
+    /// the request is rejected
+    #[default]
+    Reject,
+    /// the training split median is used
+    Median,
+    /// the value is used (the category index for categorical features)
+    Constant { value: f32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ValidRange {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+/// A feature that could not be used, reported back to the client
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureType {
+    #[default]
+    Float,
+    Int,
+    Categorical,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// one input per category
+    #[default]
+    OneHot,
+    /// category index fed to an embedding table in the model
+    Embedding,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DerivedFeature {
+    pub name: String,
+    pub op: DerivedOp,
+    /// names of the numeric features used by the op
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub normalization: Normalization,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivedOp {
+    /// a * b (e.g. queue_length * processing_time as expected wait)
+    Product,
+    /// a / b, 0 when b is 0
+    Ratio,
+    Sum,
+    Difference,
+    /// ln(1 + a)
+    Log,
+}
+
+impl DerivedOp {
+    fn arity(&self) -> usize {
+        match self {
+            DerivedOp::Log => 1,
+            _ => 2,
+        }
+    }
+
+    fn apply(&self, values: &[f32]) -> f32 {
+        match self {
+            DerivedOp::Product => values[0] * values[1],
+            DerivedOp::Ratio if values[1] == 0.0 => 0.0,
+            DerivedOp::Ratio => values[0] / values[1],
+            DerivedOp::Sum => values[0] + values[1],
+            DerivedOp::Difference => values[0] - values[1],
+            DerivedOp::Log => values[0].max(0.0).ln_1p(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Normalization {
+    MinMax {
+        min: f32,
+        max: f32,
+    },
+    ZScore {
+        mean: f32,
+        std: f32,
+    },
+    #[default]
+    None,
+}
+
+impl Normalization {
+    /// returns the (offset, scale) pair, normalized = (value - offset) / scale
+    pub fn offset_scale(&self) -> (f32, f32) {
+        match self {
+            Normalization::MinMax { min, max } => (*min, max - min),
+            Normalization::ZScore { mean, std } => (*mean, *std),
+            Normalization::None => (0.0, 1.0),
+        }
+    }
+}
+
+impl Default for FeatureSchema {
+    // the original queuemetrics columns
+    // inputs are num_processors, avg_batch_size, queue_length, process_time
+    fn default() -> Self {
+        let feature = |name: &str, min: f32, max: f32| Feature {
+            name: name.to_string(),
+            feature_type: FeatureType::Float,
+            normalization: Normalization::MinMax { min, max },
+            categories: Vec::new(),
+            encoding: Encoding::OneHot,
+            embedding_dim: None,
+            imputation: None,
+            valid_range: None,
+            median: None,
+        };
+        FeatureSchema {
+            features: vec![
+                feature("processors", 0.0, 4.0),
+                feature("avg_batch_size", 0.0, 1000.10),
+                feature("queue_length", 1.0, 100.0),
+                feature("processing_time", 1.0, 100.0),
+            ],
+            derived: Vec::new(),
+            label: "status".to_string(),
+            num_classes: 3,
+            class_names: Vec::new(),
+            imputation: Imputation::Reject,
+        }
+    }
+}
+
+impl FeatureSchema {
+    pub fn read(name: &str) -> Result<FeatureSchema, Box<dyn std::error::Error>> {
+        let json_data = File::open(name);
+        if json_data.is_err() {
+            return Err(Box::from(format!("schema file {} not found", name)));
+        }
+        let schema: Result<FeatureSchema, _> = serde_json::from_reader(json_data.unwrap());
+        if schema.is_err() {
+            return Err(Box::from(format!(
+                "parsing schema file {}",
+                schema.err().unwrap()
+            )));
+        }
+        let schema = schema.unwrap();
+        schema.validate()?;
+        Ok(schema)
+    }
+
+    /// reads the schema saved with the model, artifacts from before the schema existed use the default
+    pub fn read_artifacts(artifact_dir: &str) -> Result<FeatureSchema, Box<dyn std::error::Error>> {
+        let name = format!("{artifact_dir}/schema.json");
+        if !std::path::Path::new(&name).exists() {
+            return Ok(FeatureSchema::default());
+        }
+        FeatureSchema::read(&name)
+    }
+
+    pub fn save(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let file = File::create(name)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.features.is_empty() {
+            return Err(Box::from("schema has no features"));
+        }
+        if self.num_classes < 2 {
+            return Err(Box::from("schema num_classes should be at least 2"));
+        }
+        if !self.class_names.is_empty() && self.class_names.len() != self.num_classes {
+            return Err(Box::from(
+                "schema class_names should have num_classes entries",
+            ));
+        }
+        let mut names = std::collections::HashSet::new();
+        for name in self
+            .features
+            .iter()
+            .map(|f| &f.name)
+            .chain(self.derived.iter().map(|d| &d.name))
+        {
+            if !names.insert(name) {
+                return Err(Box::from(format!(
+                    "feature {} is defined more than once",
+                    name
+                )));
+            }
+        }
+        for feature in self.features.iter() {
+            let (_, scale) = feature.normalization.offset_scale();
+            if let Normalization::ZScore { std, .. } = feature.normalization {
+                if std < 0.0 {
+                    return Err(Box::from(format!(
+                        "feature {} normalization std {} is negative",
+                        feature.name, std
+                    )));
+                }
+            }
+            if scale == 0.0 {
+                return Err(Box::from(format!(
+                    "feature {} normalization has a zero range",
+                    feature.name
+                )));
+            }
+            if feature.name == self.label {
+                return Err(Box::from(format!(
+                    "feature {} is also the label column",
+                    feature.name
+                )));
+            }
+            if feature.feature_type == FeatureType::Categorical && feature.categories.is_empty() {
+                return Err(Box::from(format!(
+                    "categorical feature {} has no categories",
+                    feature.name
+                )));
+            }
+            if let Some(ValidRange {
+                min: Some(min),
+                max: Some(max),
+            }) = &feature.valid_range
+            {
+                if min > max {
+                    return Err(Box::from(format!(
+                        "feature {} valid_range min {} is greater than max {}",
+                        feature.name, min, max
+                    )));
+                }
+            }
+            if feature.embedding_dim == Some(0) {
+                return Err(Box::from(format!(
+                    "feature {} embedding_dim should be at least 1",
+                    feature.name
+                )));
+            }
+            if let (FeatureType::Int, Imputation::Constant { value }) = (
+                &feature.feature_type,
+                feature.imputation.as_ref().unwrap_or(&self.imputation),
+            ) {
+                if value.fract() != 0.0 {
+                    return Err(Box::from(format!(
+                        "int feature {} imputation constant {} is not an integer",
+                        feature.name, value
+                    )));
+                }
+            }
+            if let (FeatureType::Categorical, Imputation::Constant { value }) = (
+                &feature.feature_type,
+                feature.imputation.as_ref().unwrap_or(&self.imputation),
+            ) {
+                if *value < 0.0
+                    || value.fract() != 0.0
+                    || *value as usize >= feature.categories.len()
+                {
+                    return Err(Box::from(format!(
+                        "categorical feature {} imputation constant {} is not a category index below {}",
+                        feature.name,
+                        value,
+                        feature.categories.len()
+                    )));
+                }
+            }
+        }
+        for derived in self.derived.iter() {
+            if derived.inputs.len() != derived.op.arity() {
+                return Err(Box::from(format!(
+                    "derived feature {} op {:?} needs {} inputs",
+                    derived.name,
+                    derived.op,
+                    derived.op.arity()
+                )));
+            }
+            for input in derived.inputs.iter() {
+                match self.features.iter().find(|f| &f.name == input) {
+                    Some(f) if f.feature_type != FeatureType::Categorical => {}
+                    _ => {
+                        return Err(Box::from(format!(
+                            "derived feature {} input {} is not a numeric feature",
+                            derived.name, input
+                        )))
+                    }
+                }
+            }
+            let (_, scale) = derived.normalization.offset_scale();
+            if let Normalization::ZScore { std, .. } = derived.normalization {
+                if std < 0.0 {
+                    return Err(Box::from(format!(
+                        "derived feature {} normalization std {} is negative",
+                        derived.name, std
+                    )));
+                }
+            }
+            if scale == 0.0 {
+                return Err(Box::from(format!(
+                    "derived feature {} normalization has a zero range",
+                    derived.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// class names, the class index when the schema has none
+    pub fn class_names(&self) -> Vec<String> {
+        if self.class_names.is_empty() {
+            return (0..self.num_classes).map(|c| c.to_string()).collect();
+        }
+        self.class_names.clone()
+    }
+
+    /// dense model input size, embedding indexed features are not included
+    pub fn input_size(&self) -> usize {
+        let features: usize = self.features.iter().map(|f| f.width()).sum();
+        features + self.derived.len()
+    }
+
+    /// embedding tables, one per embedding indexed feature
+    pub fn embeddings(&self) -> Vec<EmbeddingSize> {
+        self.features
+            .iter()
+            .filter(|f| f.is_embedding())
+            .map(|f| EmbeddingSize {
+                categories: f.categories.len(),
+                dim: f.embedding_dim.unwrap_or(4),
+            })
+            .collect()
+    }
+
+    /// (offset, scale) per dense model input, used by the batcher normalizer
+    pub fn normalization(&self) -> (Vec<f32>, Vec<f32>) {
+        let mut offsets = Vec::new();
+        let mut scales = Vec::new();
+        for feature in self.features.iter() {
+            let (offset, scale) = match feature.feature_type {
+                FeatureType::Categorical => (0.0, 1.0),
+                _ => feature.normalization.offset_scale(),
+            };
+            for _ in 0..feature.width() {
+                offsets.push(offset);
+                scales.push(scale);
+            }
+        }
+        for derived in self.derived.iter() {
+            let (offset, scale) = derived.normalization.offset_scale();
+            offsets.push(offset);
+            scales.push(scale);
+        }
+        (offsets, scales)
+    }
+
+    /// Applies the feature pipeline to a row, appends the dense model inputs (one-hot blocks and
+    /// derived features included) and the category indexes for the embedding tables
+    pub fn encode(&self, item: &QueueMetrics, inputs: &mut Vec<f32>, categories: &mut Vec<i64>) {
+        for (feature, value) in self.features.iter().zip(item.features.iter()) {
+            match feature.feature_type {
+                FeatureType::Categorical if feature.is_embedding() => {
+                    categories.push(*value as i64)
+                }
+                FeatureType::Categorical => {
+                    for index in 0..feature.categories.len() {
+                        inputs.push(if index == *value as usize { 1.0 } else { 0.0 });
+                    }
+                }
+                _ => inputs.push(*value),
+            }
+        }
+        for derived in self.derived.iter() {
+            let values = derived
+                .inputs
+                .iter()
+                .map(|name| {
+                    self.features
+                        .iter()
+                        .position(|f| &f.name == name)
+                        .map(|i| item.features[i])
+                        .unwrap_or(0.0)
+                })
+                .collect::<Vec<_>>();
+            inputs.push(derived.op.apply(&values));
+        }
+    }
+
+    /// builds a row from a csv record, columns are looked up by header name
+    pub fn parse_record(
+        &self,
+        headers: &HashMap<String, usize>,
+        record: &csv::StringRecord,
+    ) -> Result<QueueMetrics, String> {
+        let column = |name: &str| -> Result<&str, String> {
+            let index = headers
+                .get(name)
+                .ok_or(format!("column {} not found", name))?;
+            Ok(record.get(*index).unwrap_or("").trim())
+        };
+        let field = |name: &str| -> Result<f32, String> {
+            let value = column(name)?;
+            value
+                .parse::<f32>()
+                .map_err(|_| format!("column {} value {} is not a number", name, value))
+        };
+        let mut features = Vec::with_capacity(self.features.len());
+        for feature in self.features.iter() {
+            let value = match feature.feature_type {
+                FeatureType::Categorical => feature.category_index(column(&feature.name)?),
+                _ => feature.check(field(&feature.name)?),
+            };
+            features.push(value.map_err(|e| format!("column {} {}", feature.name, e))?);
+        }
+        let label = field(&self.label)?;
+        if label < 0.0 || label.fract() != 0.0 || label as usize >= self.num_classes {
+            return Err(format!(
+                "column {} value {} is not a class index below {}",
+                self.label, label, self.num_classes
+            ));
+        }
+        Ok(QueueMetrics { features, label })
+    }
+
+    /// builds a row from a json object, the label is optional (defaults to 0).
+    /// Missing or invalid values are imputed per the feature policy, every field that can't be
+    /// used is reported.
+    pub fn parse_json(&self, data: &serde_json::Value) -> Result<QueueMetrics, Vec<FieldError>> {
+        let object = match data.as_object() {
+            Some(object) => object,
+            None => {
+                return Err(vec![FieldError {
+                    field: "body".to_string(),
+                    reason: "expected a json object".to_string(),
+                }])
+            }
+        };
+        let mut features = Vec::with_capacity(self.features.len());
+        let mut errors = Vec::new();
+        for feature in self.features.iter() {
+            let value = match (&feature.feature_type, object.get(&feature.name)) {
+                (_, None) | (_, Some(serde_json::Value::Null)) => Err("is missing".to_string()),
+                (FeatureType::Categorical, Some(serde_json::Value::String(v))) => {
+                    feature.category_index(v)
+                }
+                (FeatureType::Categorical, Some(serde_json::Value::Number(n))) => {
+                    feature.category_index(&n.to_string())
+                }
+                (FeatureType::Categorical, Some(_)) => Err("is not a category".to_string()),
+                (_, Some(serde_json::Value::Number(n))) => {
+                    feature.check(n.as_f64().unwrap_or(f64::NAN) as f32)
+                }
+                (_, Some(_)) => Err("is not a number".to_string()),
+            };
+            let value = value
+                .or_else(|reason| self.impute(feature, reason))
+                .and_then(|value| feature.check_range(value));
+            match value {
+                Ok(value) => features.push(value),
+                Err(reason) => errors.push(FieldError {
+                    field: feature.name.clone(),
+                    reason,
+                }),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let label = match object.get(&self.label) {
+            Some(serde_json::Value::Number(n)) => n.as_f64().unwrap_or(0.0) as f32,
+            _ => 0.0,
+        };
+        Ok(QueueMetrics { features, label })
+    }
+
+    fn impute(&self, feature: &Feature, reason: String) -> Result<f32, String> {
+        match feature.imputation.as_ref().unwrap_or(&self.imputation) {
+            Imputation::Reject => Err(reason),
+            Imputation::Median => match feature.median {
+                Some(median) => Ok(median),
+                None => Err(format!("{} (no training median to impute)", reason)),
+            },
+            Imputation::Constant { value } => Ok(*value),
+        }
+    }
+
+    /// A valid row (training medians, normalization offsets or the first category), used to warm up
+    /// the model before serving
+    pub fn sample(&self) -> QueueMetrics {
+        let features = self
+            .features
+            .iter()
+            .map(|f| match (f.median, &f.feature_type) {
+                (Some(median), _) => median,
+                (None, FeatureType::Categorical) => 0.0,
+                (None, _) => f.normalization.offset_scale().0,
+            })
+            .collect();
+        QueueMetrics {
+            features,
+            label: 0.0,
+        }
+    }
+
+    /// Sets the per feature training medians used by the median imputation policy
+    pub fn with_medians(mut self, items: &[QueueMetrics]) -> Self {
+        for (index, feature) in self.features.iter_mut().enumerate() {
+            let mut values: Vec<f32> = items.iter().map(|item| item.features[index]).collect();
+            if values.is_empty() {
+                continue;
+            }
+            feature.median = match feature.feature_type {
+                FeatureType::Categorical => {
+                    let mut counts = vec![0usize; feature.categories.len()];
+                    for value in values.iter() {
+                        counts[*value as usize] += 1;
+                    }
+                    counts
+                        .iter()
+                        .enumerate()
+                        .max_by_key(|(_, count)| **count)
+                        .map(|(index, _)| index as f32)
+                }
+                _ => {
+                    values.sort_by(|a, b| a.total_cmp(b));
+                    Some(values[values.len() / 2])
+                }
+            };
+        }
+        self
+    }
+}
+
+impl Feature {
+    /// the value as written in the training csv, the category name for categorical features
+    pub fn csv_value(&self, value: f32) -> String {
+        match self.feature_type {
+            FeatureType::Categorical => self
+                .categories
+                .get(value as usize)
+                .cloned()
+                .unwrap_or_default(),
+            _ => value.to_string(),
+        }
+    }
+
+    /// number of dense model inputs for this feature
+    fn width(&self) -> usize {
+        match self.feature_type {
+            FeatureType::Categorical if self.is_embedding() => 0,
+            FeatureType::Categorical => self.categories.len(),
+            _ => 1,
+        }
+    }
+
+    fn is_embedding(&self) -> bool {
+        self.feature_type == FeatureType::Categorical && self.encoding == Encoding::Embedding
+    }
+
+    fn category_index(&self, value: &str) -> Result<f32, String> {
+        self.categories
+            .iter()
+            .position(|c| c == value)
+            .map(|index| index as f32)
+            .ok_or(format!("category {} is not known", value))
+    }
+
+    fn check(&self, value: f32) -> Result<f32, String> {
+        if !value.is_finite() {
+            return Err("is not a finite number".to_string());
+        }
+        if self.feature_type == FeatureType::Int && value.fract() != 0.0 {
+            return Err("should be an integer".to_string());
+        }
+        Ok(value)
+    }
+
+    fn check_range(&self, value: f32) -> Result<f32, String> {
+        let range = match (&self.feature_type, &self.valid_range) {
+            (FeatureType::Categorical, _) | (_, None) => return Ok(value),
+            (_, Some(range)) => range,
+        };
+        if range.min.is_some_and(|min| value < min) || range.max.is_some_and(|max| value > max) {
+            return Err(format!(
+                "value {} is outside the valid range [{}, {}]",
+                value,
+                range.min.map_or("-inf".to_string(), |v| v.to_string()),
+                range.max.map_or("inf".to_string(), |v| v.to_string())
+            ));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> FeatureSchema {
+        serde_json::from_value(json!({
+            "features": [
+                { "name": "processors", "type": "int", "normalization": { "method": "min_max", "min": 0.0, "max": 4.0 } },
+                { "name": "queue_length" },
+                { "name": "region", "type": "categorical", "categories": ["eu", "us"] }
+            ],
+            "label": "status",
+            "num_classes": 3
+        }))
+        .unwrap()
+    }
+
+    fn record(header: &[&str], values: &[&str]) -> (HashMap<String, usize>, csv::StringRecord) {
+        let headers = header
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.to_string(), index))
+            .collect();
+        (headers, csv::StringRecord::from(values.to_vec()))
+    }
+
+    #[test]
+    fn parses_a_schema_file_layout() {
+        let schema = schema();
+        assert!(schema.validate().is_ok());
+        assert_eq!(schema.features[1].feature_type, FeatureType::Float);
+        assert_eq!(schema.imputation, Imputation::Reject);
+        // one input per numeric feature, one per category
+        assert_eq!(schema.input_size(), 4);
+        assert_eq!(schema.class_names(), vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn parses_csv_records_by_header_name() {
+        let schema = schema();
+        let (headers, row) = record(
+            &["status", "region", "queue_length", "processors"],
+            &["2", "us", "12.5", "3"],
+        );
+        let item = schema.parse_record(&headers, &row).unwrap();
+        assert_eq!(item.features, vec![3.0, 12.5, 1.0]);
+        assert_eq!(item.label, 2.0);
+    }
+
+    #[test]
+    fn rejects_invalid_csv_records() {
+        let schema = schema();
+        let header = ["processors", "queue_length", "region", "status"];
+        for (values, error) in [
+            (
+                ["1.5", "1", "eu", "0"],
+                "column processors should be an integer",
+            ),
+            (
+                ["1", "x", "eu", "0"],
+                "column queue_length value x is not a number",
+            ),
+            (
+                ["1", "1", "asia", "0"],
+                "column region category asia is not known",
+            ),
+            (
+                ["1", "1", "eu", "3"],
+                "column status value 3 is not a class index below 3",
+            ),
+        ] {
+            let (headers, row) = record(&header, &values);
+            assert_eq!(schema.parse_record(&headers, &row).unwrap_err(), error);
+        }
+        let (headers, row) = record(&["processors", "status"], &["1", "0"]);
+        assert_eq!(
+            schema.parse_record(&headers, &row).unwrap_err(),
+            "column queue_length not found"
+        );
+    }
+
+    #[test]
+    fn parses_json_objects() {
+        let schema = schema();
+        let item = schema
+            .parse_json(&json!({ "processors": 2, "queue_length": 7.5, "region": "eu" }))
+            .unwrap();
+        assert_eq!(item.features, vec![2.0, 7.5, 0.0]);
+        assert_eq!(item.label, 0.0);
+    }
+
+    #[test]
+    fn reports_every_invalid_json_field() {
+        let schema = schema();
+        let errors = schema
+            .parse_json(&json!({ "processors": "two", "region": 1 }))
+            .unwrap_err();
+        let fields: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|e| (e.field.as_str(), e.reason.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("processors", "is not a number"),
+                ("queue_length", "is missing"),
+                ("region", "category 1 is not known"),
+            ]
+        );
+        let errors = schema.parse_json(&json!([1, 2, 3])).unwrap_err();
+        assert_eq!(errors[0].field, "body");
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let mut no_categories = schema();
+        no_categories.features[2].categories.clear();
+        let mut label_feature = schema();
+        label_feature.features[1].name = "status".to_string();
+        let mut zero_range = schema();
+        zero_range.features[0].normalization = Normalization::MinMax { min: 1.0, max: 1.0 };
+        let mut one_class = schema();
+        one_class.num_classes = 1;
+        let mut duplicate = schema();
+        duplicate.features[1].name = "processors".to_string();
+        let mut inverted_range = schema();
+        inverted_range.features[1].valid_range = Some(ValidRange {
+            min: Some(10.0),
+            max: Some(1.0),
+        });
+        let mut zero_embedding = schema();
+        zero_embedding.features[2].encoding = Encoding::Embedding;
+        zero_embedding.features[2].embedding_dim = Some(0);
+        let mut negative_std = schema();
+        negative_std.features[1].normalization = Normalization::ZScore {
+            mean: 0.0,
+            std: -2.0,
+        };
+        let mut fractional_int = schema();
+        fractional_int.features[0].imputation = Some(Imputation::Constant { value: 1.5 });
+        for (schema, error) in [
+            (
+                no_categories,
+                "categorical feature region has no categories",
+            ),
+            (label_feature, "feature status is also the label column"),
+            (
+                zero_range,
+                "feature processors normalization has a zero range",
+            ),
+            (one_class, "schema num_classes should be at least 2"),
+            (duplicate, "feature processors is defined more than once"),
+            (
+                inverted_range,
+                "feature queue_length valid_range min 10 is greater than max 1",
+            ),
+            (
+                zero_embedding,
+                "feature region embedding_dim should be at least 1",
+            ),
+            (
+                negative_std,
+                "feature queue_length normalization std -2 is negative",
+            ),
+            (
+                fractional_int,
+                "int feature processors imputation constant 1.5 is not an integer",
+            ),
+        ] {
+            assert_eq!(schema.validate().unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn imputes_missing_values_per_feature_policy() {
+        let mut schema = schema();
+        schema.imputation = Imputation::Constant { value: 1.0 };
+        schema.features[1].imputation = Some(Imputation::Median);
+        schema.features[1].median = Some(40.0);
+        let item = schema.parse_json(&json!({ "processors": null })).unwrap();
+        assert_eq!(item.features, vec![1.0, 40.0, 1.0]);
+
+        schema.features[1].median = None;
+        let errors = schema.parse_json(&json!({})).unwrap_err();
+        assert_eq!(errors[0].field, "queue_length");
+        assert_eq!(
+            errors[0].reason,
+            "is missing (no training median to impute)"
+        );
+    }
+
+    #[test]
+    fn rejects_values_outside_the_valid_range() {
+        let mut schema = schema();
+        schema.features[1].valid_range = Some(ValidRange {
+            min: Some(0.0),
+            max: None,
+        });
+        // an imputed value is range checked too
+        schema.features[1].imputation = Some(Imputation::Constant { value: -1.0 });
+        let errors = schema
+            .parse_json(&json!({ "processors": 1, "region": "eu" }))
+            .unwrap_err();
+        assert_eq!(
+            errors[0].reason,
+            "value -1 is outside the valid range [0, inf]"
+        );
+    }
+
+    #[test]
+    fn sets_training_medians_and_most_frequent_categories() {
+        let row = |processors: f32, region: f32| QueueMetrics {
+            features: vec![processors, 10.0, region],
+            label: 0.0,
+        };
+        let schema = schema().with_medians(&[row(1.0, 1.0), row(4.0, 0.0), row(2.0, 1.0)]);
+        assert_eq!(schema.features[0].median, Some(2.0));
+        assert_eq!(schema.features[2].median, Some(1.0));
+    }
+
+    #[test]
+    fn rejects_constant_imputation_that_is_not_a_category() {
+        for value in [2.0, 0.5, -1.0] {
+            let mut schema = schema();
+            schema.features[2].imputation = Some(Imputation::Constant { value });
+            assert_eq!(
+                schema.validate().unwrap_err().to_string(),
+                format!(
+                    "categorical feature region imputation constant {} is not a category index below 2",
+                    value
+                )
+            );
+        }
+        // the schema wide constant applies to categorical features without an override
+        let mut schema = schema();
+        schema.imputation = Imputation::Constant { value: 5.0 };
+        assert!(schema.validate().is_err());
+        schema.features[2].imputation = Some(Imputation::Constant { value: 1.0 });
+        assert!(schema.validate().is_ok());
+    }
+}
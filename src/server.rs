@@ -1,21 +1,38 @@
-use burn::{
-    data::dataloader::batcher::Batcher,
-    module::Module,
-    record::{CompactRecorder, Recorder},
-};
-
-use burn_cuda::{Cuda, CudaDevice};
-
 use crate::{
-    dataset::{QueueMetrics, QueueMetricsBatcher, NUM_CLASSES, NUM_FEATURES},
-    model::{ModelConfig, ModelRecord},
-    MAP_LOOKUP,
+    accesslog::{self, AccessLogEntry, PredictedClass},
+    auth, batchqueue,
+    dataset::QueueMetrics,
+    drift::{self, FeatureDrift},
+    feedback,
+    listener::ConnectionInfo,
+    metrics,
+    model::ModelConfig,
+    modelstore,
+    predictionlog::{self, PredictionRecord},
+    ratelimit,
+    schema::{FeatureSchema, FieldError},
+    serviceerror::ServiceError,
+    training::ValidMetrics,
 };
 
 use custom_logger as log;
-use http::{Method, Request, Response, StatusCode};
-use http_body_util::{BodyExt, Full};
+use http::{Method, Request, Response};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper::body::{Bytes, Incoming};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// max size of an inference request body, and the time to receive it
+static MAX_BODY_SIZE: AtomicUsize = AtomicUsize::new(64 * 1024);
+static BODY_READ_TIMEOUT_MS: AtomicU64 = AtomicU64::new(30_000);
+const REQUEST_ID_HEADER: &str = "x-request-id";
+static REQUEST_COUNT: AtomicU64 = AtomicU64::new(0);
+// client certificate subjects or san entries allowed to call the service (mTLS)
+static ALLOWED_CLIENTS: OnceLock<Vec<String>> = OnceLock::new();
+// false when http/1.0 is left out of the tls policy alpn list
+static HTTP10_ALLOWED: AtomicBool = AtomicBool::new(true);
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct InferenceResponse {
@@ -25,62 +42,412 @@ pub struct InferenceResponse {
     pub predicted: usize,
 }
 
-// inference endpoint
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct StatusResponse {
+    #[serde(rename = "status")]
+    pub status: String,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ModelInfoResponse {
+    pub version: String,
+    pub model: ModelConfig,
+    pub schema: FeatureSchema,
+    pub class_names: Vec<String>,
+    pub normalization: NormalizationStats,
+    pub training_metrics: Option<ValidMetrics>,
+    /// drift scores of recent requests, when the model has training stats
+    pub drift: Option<Vec<FeatureDrift>>,
+}
+
+/// (value - offset) / scale per dense model input, and the training medians per feature
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct NormalizationStats {
+    pub offset: Vec<f32>,
+    pub scale: Vec<f32>,
+    pub medians: Vec<Option<f32>>,
+}
+
+/// Id of the request, from the X-Request-Id header or generated, added to the request extensions
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// inference endpoint, every failure is returned as a json error response
 pub async fn inference_service(
-    req: Request<Incoming>,
-) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    let mut response = Response::new(Full::default());
-    let hm = MAP_LOOKUP.lock().unwrap().clone();
-    let artifact_dir = hm.unwrap().get("artifact_dir").unwrap().clone();
-    let device = CudaDevice::default();
-    type MyBackend = Cuda<f32, i32>;
-    let record: ModelRecord<MyBackend> = CompactRecorder::new()
-        .load(format!("{artifact_dir}/model").into(), &device)
-        .expect("trained model should exist; run train first");
-    // parameters are features,classes,hidden size
-    let model = ModelConfig::new(NUM_FEATURES, NUM_CLASSES, 256)
-        .init(&device)
-        .load_record(record);
-    let batcher = QueueMetricsBatcher::new(device.clone());
+    mut req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let generated = new_request_id();
+    let request_id = client_request_id(&req).unwrap_or(generated);
+    let _in_flight = metrics::request_started();
+    let start = Instant::now();
+    let route_label = route_label(req.uri().path());
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let version = format!("{:?}", req.version());
+    let info = req.extensions().get::<ConnectionInfo>().cloned();
+    let client = info
+        .as_ref()
+        .map(|info| info.client_label())
+        .unwrap_or_default();
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    let mut principal = None;
+    let result = match admit(&mut req) {
+        Ok(name) => {
+            principal = name;
+            route(req).await
+        }
+        Err(err) => Err(err),
+    };
+    let mut response = match result {
+        Ok(response) => response,
+        Err(err) => {
+            if err.status().is_server_error() {
+                log::error!("request {} : client {} : {}", request_id, client, err);
+            } else {
+                log::warn!("request {} : client {} : {}", request_id, client, err);
+            }
+            err.into_response(&request_id)
+        }
+    };
+    let latency = start.elapsed().as_secs_f64();
+    metrics::observe_request(route_label, response.status().as_u16(), latency);
+    let predicted = response.extensions().get::<PredictedClass>();
+    accesslog::write(&AccessLogEntry {
+        timestamp: accesslog::now(),
+        request_id: &request_id,
+        remote_addr: info
+            .as_ref()
+            .map(|info| info.remote_addr.to_string())
+            .unwrap_or_default(),
+        client: principal.or_else(|| {
+            info.as_ref()
+                .and_then(|info| info.client.as_ref())
+                .map(|client| client.subject.clone())
+        }),
+        method: method.as_str(),
+        path: &path,
+        version,
+        status: response.status().as_u16(),
+        latency_ms: (latency * 1_000_000.0).round() / 1000.0,
+        predicted_class: predicted.map(|p| p.class.as_str()),
+        confidence: predicted.map(|p| p.confidence),
+    });
+    if let Ok(value) = http::HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
 
+// the caller's id is kept when it's a usable header value of a sane length
+fn client_request_id<B>(req: &Request<B>) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?.trim();
+    if id.is_empty() || id.len() > 128 || !id.chars().all(|c| c.is_ascii_graphic()) {
+        return None;
+    }
+    Some(id.to_string())
+}
+
+// unknown paths share one label so scanners can't grow the metric series
+pub fn route_label(path: &str) -> &'static str {
+    match path {
+        "/inference" => "/inference",
+        "/feedback" => "/feedback",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/model" => "/model",
+        "/metrics" => "/metrics",
+        _ => "other",
+    }
+}
+
+/// Restricts the service to the given client certificate subjects or san entries
+pub fn set_allowed_clients(allowed: Vec<String>) {
+    let _ = ALLOWED_CLIENTS.set(allowed);
+}
+
+// probes stay open, kubelet doesn't present a client certificate
+fn authorize(req: &Request<Incoming>) -> Result<(), ServiceError> {
+    let allowed = match ALLOWED_CLIENTS.get() {
+        Some(allowed) if !allowed.is_empty() => allowed,
+        _ => return Ok(()),
+    };
+    if matches!(req.uri().path(), "/healthz" | "/readyz") {
+        return Ok(());
+    }
+    let client = req
+        .extensions()
+        .get::<ConnectionInfo>()
+        .and_then(|info| info.client.as_ref());
+    match client {
+        Some(client) if client.matches(allowed) => Ok(()),
+        Some(client) => Err(ServiceError::Forbidden(format!(
+            "client {} is not allowed",
+            client.subject
+        ))),
+        None => Err(ServiceError::Forbidden(
+            "a client certificate is required".to_string(),
+        )),
+    }
+}
+
+pub fn set_body_limits(max_body_size: usize, read_timeout: Duration) {
+    MAX_BODY_SIZE.store(max_body_size, Ordering::Relaxed);
+    BODY_READ_TIMEOUT_MS.store(read_timeout.as_millis() as u64, Ordering::Relaxed);
+}
+
+pub fn set_http10_allowed(allowed: bool) {
+    HTTP10_ALLOWED.store(allowed, Ordering::Relaxed);
+}
+
+// version, client certificate, credential and rate limit checks, returns the authenticated caller
+// name for the access log
+fn admit(req: &mut Request<Incoming>) -> Result<Option<String>, ServiceError> {
+    if req.version() == http::Version::HTTP_10 && !HTTP10_ALLOWED.load(Ordering::Relaxed) {
+        return Err(ServiceError::VersionNotSupported("HTTP/1.0".to_string()));
+    }
+    authorize(req)?;
+    let route = route_label(req.uri().path());
+    let principal = match auth::authenticate(req.headers(), req.uri().path()) {
+        Ok(principal) => principal,
+        // a failed attempt takes a token from the ip (or client certificate) bucket, so guessing
+        // credentials is throttled like any other request
+        Err(err) => {
+            ratelimit::check(req, route)?;
+            return Err(err);
+        }
+    };
+    let name = principal.as_ref().map(|principal| principal.name.clone());
+    if let Some(principal) = principal {
+        req.extensions_mut().insert(principal);
+    }
+    ratelimit::check(req, route)?;
+    Ok(name)
+}
+
+async fn route(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, ServiceError> {
     match (req.method(), req.uri().path()) {
         // inference.
-        (&Method::POST, "/inference") => {
-            let data = req.into_body().collect().await?.to_bytes();
-            let qm: QueueMetrics = serde_json::from_slice(&data).unwrap();
-            log::debug!("queuemetrics {:?}", qm);
-            let items = vec![qm];
-            let batch = batcher.batch(items.clone(), &device);
-            let predicted = model.forward(batch.inputs.clone());
-            let targets = batch.targets;
-            let expected = targets.into_data().iter::<f32>().collect::<Vec<_>>();
-            let predicted = predicted
-                .iter_dim(0)
-                .map(|item| item.into_data().into_vec::<f32>())
-                .collect::<Vec<_>>();
-            let (predicted_max_index, _) = find_max_index(predicted[0].as_ref().unwrap());
-            log::info!(
-                "expected {} : predicted {}",
-                expected[0],
-                predicted_max_index
-            );
-            let ir = InferenceResponse {
-                expected: expected[0] as usize,
-                predicted: predicted_max_index,
-            };
-            *response.body_mut() = Full::from(serde_json::to_string(&ir).unwrap());
+        (&Method::POST, "/inference") => inference(req).await,
+        // true label for a served prediction
+        (&Method::POST, "/feedback") => feedback(req).await,
+        // process is alive
+        (&Method::GET, "/healthz") => json_response(&StatusResponse {
+            status: "ok".to_string(),
+        }),
+        // model is loaded and warmed up
+        (&Method::GET, "/readyz") => readiness(),
+        (&Method::GET, "/model") => model_info(),
+        (&Method::GET, "/metrics") => metrics_response(),
+        (method, "/inference" | "/feedback") => {
+            Err(ServiceError::MethodNotAllowed(method.to_string(), "POST"))
         }
-        _ => {
-            *response.status_mut() = StatusCode::NOT_FOUND;
+        (method, "/healthz" | "/readyz" | "/model" | "/metrics") => {
+            Err(ServiceError::MethodNotAllowed(method.to_string(), "GET"))
         }
+        (_, path) => Err(ServiceError::NotFound(format!("route {}", path))),
+    }
+}
+
+fn readiness() -> Result<Response<Full<Bytes>>, ServiceError> {
+    if !modelstore::is_ready() {
+        return Err(ServiceError::Unavailable("model is not ready".to_string()));
+    }
+    json_response(&StatusResponse {
+        status: "ready".to_string(),
+    })
+}
+
+fn model_info() -> Result<Response<Full<Bytes>>, ServiceError> {
+    let loaded = modelstore::get()?;
+    let (offset, scale) = loaded.schema.normalization();
+    let info = ModelInfoResponse {
+        version: loaded.version.clone(),
+        model: loaded.config.model.clone(),
+        schema: (*loaded.schema).clone(),
+        class_names: loaded.schema.class_names(),
+        normalization: NormalizationStats {
+            offset,
+            scale,
+            medians: loaded.schema.features.iter().map(|f| f.median).collect(),
+        },
+        training_metrics: loaded.metrics.clone(),
+        drift: drift::report(),
+    };
+    json_response(&info)
+}
+
+fn metrics_response() -> Result<Response<Full<Bytes>>, ServiceError> {
+    let mut response = Response::new(Full::from(metrics::render()));
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok(response)
+}
+
+async fn inference(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, ServiceError> {
+    let start = Instant::now();
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_default();
+    predictionlog::check_unused(&request_id)?;
+    let data = read_body(req).await?;
+    let loaded = modelstore::get()?;
+    let schema = &loaded.schema;
+
+    let qm: QueueMetrics = match serde_json::from_slice::<serde_json::Value>(&data) {
+        Ok(value) => schema.parse_json(&value).map_err(|errors| {
+            ServiceError::BadRequest("invalid queuemetrics".to_string(), errors)
+        })?,
+        Err(err) => {
+            return Err(ServiceError::BadRequest(
+                "invalid json".to_string(),
+                vec![FieldError {
+                    field: "body".to_string(),
+                    reason: err.to_string(),
+                }],
+            ))
+        }
+    };
+    log::debug!("queuemetrics {:?}", qm);
+    metrics::observe_stage("parse", start.elapsed().as_secs_f64());
+
+    let features = qm.features.clone();
+    let prediction = batchqueue::predict(qm).await?;
+    // only rows the model served count towards drift, rejected or failed requests don't
+    drift::observe(&features);
+    let predicted = &prediction.logits;
+    let (predicted_max_index, _) = find_max_index(predicted);
+    let class = schema
+        .class_names()
+        .get(predicted_max_index)
+        .cloned()
+        .unwrap_or_else(|| predicted_max_index.to_string());
+    let probabilities = softmax(predicted);
+    let confidence = probabilities
+        .get(predicted_max_index)
+        .cloned()
+        .unwrap_or(0.0);
+    metrics::observe_prediction(&class, confidence);
+    if predictionlog::enabled() {
+        predictionlog::write(PredictionRecord {
+            request_id,
+            timestamp: accesslog::now(),
+            model_version: loaded.version.clone(),
+            schema: loaded.schema.clone(),
+            features,
+            predicted: predicted_max_index,
+            probabilities,
+        });
+    }
+    log::debug!(
+        "expected {} : predicted {}",
+        prediction.expected,
+        predicted_max_index
+    );
+    let ir = InferenceResponse {
+        expected: prediction.expected,
+        predicted: predicted_max_index,
     };
+    let mut response = json_response(&ir)?;
+    response
+        .extensions_mut()
+        .insert(PredictedClass { class, confidence });
     Ok(response)
 }
 
+async fn feedback(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, ServiceError> {
+    let data = read_body(req).await?;
+    let loaded = modelstore::get()?;
+    let value = serde_json::from_slice::<serde_json::Value>(&data).map_err(|err| {
+        ServiceError::BadRequest(
+            "invalid json".to_string(),
+            vec![FieldError {
+                field: "body".to_string(),
+                reason: err.to_string(),
+            }],
+        )
+    })?;
+    let (request_id, actual) = feedback::parse(&loaded.schema, &value)?;
+    // the prediction may have to be read back from the log files
+    let response =
+        tokio::task::spawn_blocking(move || feedback::record(&loaded.schema, &request_id, actual))
+            .await
+            .map_err(|e| ServiceError::Internal(format!("feedback task failed: {}", e)))??;
+    json_response(&response)
+}
+
+// reads the request body, up to MAX_BODY_SIZE within BODY_READ_TIMEOUT_MS
+async fn read_body(req: Request<Incoming>) -> Result<Bytes, ServiceError> {
+    let max_body_size = MAX_BODY_SIZE.load(Ordering::Relaxed);
+    let read_timeout = Duration::from_millis(BODY_READ_TIMEOUT_MS.load(Ordering::Relaxed));
+    let content_length = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > max_body_size) {
+        return Err(ServiceError::PayloadTooLarge(max_body_size));
+    }
+    let body = Limited::new(req.into_body(), max_body_size).collect();
+    let body = match tokio::time::timeout(read_timeout, body).await {
+        Ok(body) => body,
+        Err(_) => return Err(ServiceError::RequestTimeout(read_timeout.as_secs())),
+    };
+    match body {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(err) if err.downcast_ref::<LengthLimitError>().is_some() => {
+            Err(ServiceError::PayloadTooLarge(max_body_size))
+        }
+        Err(err) => Err(ServiceError::BadRequest(
+            format!("failed to read request body: {}", err),
+            Vec::new(),
+        )),
+    }
+}
+
+fn json_response<T: serde::Serialize>(value: &T) -> Result<Response<Full<Bytes>>, ServiceError> {
+    let json = serde_json::to_string(value).map_err(|e| ServiceError::Internal(e.to_string()))?;
+    let mut response = Response::new(Full::from(json));
+    response.headers_mut().insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/json"),
+    );
+    Ok(response)
+}
+
+/// Requests received since startup
+pub fn request_count() -> u64 {
+    REQUEST_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn new_request_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+    format!("{:016x}-{:08x}", nanos, count)
+}
+
+// class probabilities, the model returns logits
+fn softmax(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f64> = logits.iter().map(|l| ((l - max) as f64).exp()).collect();
+    let sum: f64 = exp.iter().sum();
+    if sum > 0.0 {
+        exp.iter().map(|e| e / sum).collect()
+    } else {
+        vec![0.0; logits.len()]
+    }
+}
+
 fn find_max_index(input: &Vec<f32>) -> (usize, f32) {
     let mut max_index = 0;
     let mut index = 0;
-    let mut max = 0.0f32;
+    let mut max = f32::NEG_INFINITY;
     for item in input.iter() {
         if item > &max {
             max_index = index;
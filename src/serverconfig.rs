@@ -1,4 +1,5 @@
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -9,7 +10,381 @@ pub struct Parameters {
     pub log_level: String,
     pub certs_dir: Option<String>,
     pub cert_mode: String,
+    /// enabled (default) serves https on port, disabled serves plain http/1.1 and h2c on port
+    pub tls: Option<String>,
+    /// optional plain http listener next to the https one
+    pub http_port: Option<String>,
+    /// passphrase source for an encrypted PKCS#8 ssl.key, env:NAME or file:PATH
+    pub key_passphrase: Option<String>,
+    /// warn at startup (and on reload) when the certificate expires within this many days (default 30)
+    pub cert_expiry_warning_days: Option<i64>,
+    /// seconds between checks for a new ssl.cert/ssl.key pair (default 60, 0 reloads on SIGHUP only)
+    pub cert_reload_interval: Option<u64>,
+    /// json access log line per request on stdout (default true)
+    pub access_log: Option<bool>,
+    /// input drift detection against the training stats (defaults when not set)
+    pub drift: Option<Drift>,
+    /// served predictions written to rotating files, not written when not set
+    pub prediction_log: Option<PredictionLog>,
+    /// seconds /readyz reports not ready before the listeners close on SIGTERM/SIGINT (default 5)
+    pub shutdown_delay: Option<u64>,
+    /// seconds open connections get to finish their requests on shutdown (default 30)
+    pub shutdown_timeout: Option<u64>,
+    /// connection, timeout and request size limits (defaults when not set)
+    pub limits: Option<Limits>,
+    /// micro batching of concurrent inference requests (defaults when not set)
+    pub batching: Option<Batching>,
+    /// threads running forward passes and the request queue in front of them (defaults when not set)
+    pub inference_pool: Option<InferencePool>,
+    /// tls versions, cipher suites, alpn and session resumption (rustls defaults when not set)
+    pub tls_policy: Option<TlsPolicy>,
+    /// api key and bearer token authentication, the endpoints are open when not set
+    pub auth: Option<Auth>,
+    /// per client token bucket rate limits by route, no limits when not set
+    pub rate_limits: Option<RateLimits>,
+    /// client certificate verification (mTLS), no client auth when not set
+    pub client_auth: Option<ClientAuth>,
     pub artifacts_dir: String,
+    /// feature schema file used for training (defaults to the queuemetrics columns)
+    pub schema: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientAuth {
+    /// optional (clients without a certificate are accepted) or required
+    pub mode: String,
+    /// ca bundle in certs_dir used to verify client certificates
+    #[serde(default = "default_ca_bundle")]
+    pub ca_bundle: String,
+    /// subjects or san entries (i.e DNS:client.example.com) allowed to call the service,
+    /// any verified client when empty
+    #[serde(default)]
+    pub allowed: Vec<String>,
+}
+
+fn default_ca_bundle() -> String {
+    "ca.cert".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TlsPolicy {
+    /// 1.2 (default) or 1.3
+    #[serde(default = "default_min_version")]
+    pub min_version: String,
+    /// rustls cipher suite names (i.e TLS13_AES_256_GCM_SHA384), all ring suites when empty
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// alpn protocols in preference order, http/1.0 requests are refused when it's not listed
+    #[serde(default = "default_alpn")]
+    pub alpn: Vec<String>,
+    /// stateless session resumption with tickets
+    #[serde(default)]
+    pub session_tickets: bool,
+    /// stateful session resumption cache entries, 0 disables the cache
+    #[serde(default = "default_session_cache_size")]
+    pub session_cache_size: usize,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        TlsPolicy {
+            min_version: default_min_version(),
+            cipher_suites: Vec::new(),
+            alpn: default_alpn(),
+            session_tickets: false,
+            session_cache_size: default_session_cache_size(),
+        }
+    }
+}
+
+fn default_min_version() -> String {
+    "1.2".to_string()
+}
+
+fn default_alpn() -> Vec<String> {
+    vec![
+        "h2".to_string(),
+        "http/1.1".to_string(),
+        "http/1.0".to_string(),
+    ]
+}
+
+fn default_session_cache_size() -> usize {
+    256
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Limits {
+    /// open connections across all listeners, further connections wait in the accept backlog
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// seconds
+    #[serde(default = "default_tls_handshake_timeout")]
+    pub tls_handshake_timeout: u64,
+    /// seconds to receive the request headers (http/1)
+    #[serde(default = "default_header_read_timeout")]
+    pub header_read_timeout: u64,
+    /// seconds to receive the request body (408 when exceeded)
+    #[serde(default = "default_body_read_timeout")]
+    pub body_read_timeout: u64,
+    /// bytes (413 when exceeded)
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// concurrent streams per h2 connection
+    #[serde(default = "default_http2_max_concurrent_streams")]
+    pub http2_max_concurrent_streams: u32,
+    /// bytes of decoded headers per h2 request
+    #[serde(default = "default_http2_max_header_list_size")]
+    pub http2_max_header_list_size: u32,
+    /// seconds between h2 keep-alive pings on an idle connection, 0 disables them
+    #[serde(default = "default_http2_keep_alive_interval")]
+    pub http2_keep_alive_interval: u64,
+    /// seconds to wait for a ping ack before the h2 connection is closed
+    #[serde(default = "default_http2_keep_alive_timeout")]
+    pub http2_keep_alive_timeout: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: default_max_connections(),
+            tls_handshake_timeout: default_tls_handshake_timeout(),
+            header_read_timeout: default_header_read_timeout(),
+            body_read_timeout: default_body_read_timeout(),
+            max_body_size: default_max_body_size(),
+            http2_max_concurrent_streams: default_http2_max_concurrent_streams(),
+            http2_max_header_list_size: default_http2_max_header_list_size(),
+            http2_keep_alive_interval: default_http2_keep_alive_interval(),
+            http2_keep_alive_timeout: default_http2_keep_alive_timeout(),
+        }
+    }
+}
+
+fn default_max_connections() -> usize {
+    1024
+}
+
+fn default_tls_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_header_read_timeout() -> u64 {
+    10
+}
+
+fn default_body_read_timeout() -> u64 {
+    30
+}
+
+fn default_max_body_size() -> usize {
+    64 * 1024
+}
+
+fn default_http2_max_concurrent_streams() -> u32 {
+    100
+}
+
+fn default_http2_max_header_list_size() -> u32 {
+    16 * 1024
+}
+
+fn default_http2_keep_alive_interval() -> u64 {
+    60
+}
+
+fn default_http2_keep_alive_timeout() -> u64 {
+    20
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Batching {
+    /// rows per forward pass, 1 disables batching
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    /// milliseconds the first request of a batch waits for others to join
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Batching {
+            max_batch_size: default_max_batch_size(),
+            max_wait_ms: default_max_wait_ms(),
+        }
+    }
+}
+
+fn default_max_batch_size() -> usize {
+    32
+}
+
+fn default_max_wait_ms() -> u64 {
+    2
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InferencePool {
+    /// batches running at the same time, each on a blocking thread
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// requests waiting for a batch, further requests get 503
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+}
+
+impl Default for InferencePool {
+    fn default() -> Self {
+        InferencePool {
+            workers: default_workers(),
+            queue_size: default_queue_size(),
+        }
+    }
+}
+
+fn default_workers() -> usize {
+    2
+}
+
+fn default_queue_size() -> usize {
+    256
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Auth {
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    pub jwt: Option<JwtAuth>,
+    /// /metrics needs the admin scope when set, it's open by default so prometheus can scrape it
+    #[serde(default)]
+    pub protect_metrics: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    /// shown in logs instead of the key
+    pub name: String,
+    /// hex encoded sha256 of the key
+    pub sha256: String,
+    /// infer and/or admin
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JwtAuth {
+    /// public keys the tokens are signed with
+    pub jwks_file: String,
+    /// expected iss claim, not checked when not set
+    pub issuer: Option<String>,
+    /// expected aud claim, not checked when not set
+    pub audience: Option<String>,
+    /// claim holding the scopes, a space separated string or a list
+    #[serde(default = "default_scopes_claim")]
+    pub scopes_claim: String,
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["infer".to_string()]
+}
+
+fn default_scopes_claim() -> String {
+    "scope".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimits {
+    /// auto (api key, then client certificate, then ip), ip, api_key or client_cert
+    #[serde(default = "default_key_by")]
+    pub key_by: String,
+    /// limits by route path, "*" applies to routes without their own entry
+    pub routes: HashMap<String, RateLimit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimit {
+    /// tokens added to each client bucket per second
+    pub requests_per_second: f64,
+    /// bucket size, the requests a client can send at once
+    pub burst: u32,
+}
+
+fn default_key_by() -> String {
+    "auto".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PredictionLog {
+    pub dir: String,
+    /// csv (training csv columns) or jsonl
+    #[serde(default = "default_prediction_log_format")]
+    pub format: String,
+    /// bytes before a new file is started
+    #[serde(default = "default_max_file_size")]
+    pub max_file_size: u64,
+    /// files kept in dir, the oldest are removed (0 keeps all)
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_prediction_log_format() -> String {
+    "csv".to_string()
+}
+
+fn default_max_file_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_max_files() -> usize {
+    10
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Drift {
+    #[serde(default = "default_drift_enabled")]
+    pub enabled: bool,
+    /// recent requests compared with the training split
+    #[serde(default = "default_drift_window")]
+    pub window: usize,
+    #[serde(default = "default_psi_threshold")]
+    pub psi_threshold: f64,
+    #[serde(default = "default_ks_threshold")]
+    pub ks_threshold: f64,
+    /// share of values outside the training min/max
+    #[serde(default = "default_out_of_range_threshold")]
+    pub out_of_range_threshold: f64,
+}
+
+impl Default for Drift {
+    fn default() -> Self {
+        Drift {
+            enabled: default_drift_enabled(),
+            window: default_drift_window(),
+            psi_threshold: default_psi_threshold(),
+            ks_threshold: default_ks_threshold(),
+            out_of_range_threshold: default_out_of_range_threshold(),
+        }
+    }
+}
+
+fn default_drift_enabled() -> bool {
+    true
+}
+
+fn default_drift_window() -> usize {
+    1000
+}
+
+fn default_psi_threshold() -> f64 {
+    0.2
+}
+
+fn default_ks_threshold() -> f64 {
+    0.1
+}
+
+fn default_out_of_range_threshold() -> f64 {
+    0.05
 }
 
 pub trait ConfigInterface {
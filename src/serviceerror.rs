@@ -0,0 +1,147 @@
+use crate::schema::FieldError;
+use http::{Response, StatusCode};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use std::fmt;
+
+/// Errors returned by the inference service, each maps to a status code and a json error body
+#[derive(Clone, Debug)]
+pub enum ServiceError {
+    /// the request body or its fields can't be used (400)
+    BadRequest(String, Vec<FieldError>),
+    /// missing or invalid api key or token (401)
+    Unauthorized(String),
+    /// the client is not allowed to call the service (403)
+    Forbidden(String),
+    /// unknown route or resource (404)
+    NotFound(String),
+    /// known route, wrong method (405), the allowed method is returned in the allow header
+    MethodNotAllowed(String, &'static str),
+    /// the request clashes with what was already recorded (409)
+    Conflict(String),
+    /// the body wasn't received within the read timeout in seconds (408)
+    RequestTimeout(u64),
+    /// the body is over the size limit (413)
+    PayloadTooLarge(usize),
+    /// the client is over its rate limit, seconds until it can retry (429)
+    TooManyRequests(u64),
+    /// unexpected failure in the service (500)
+    Internal(String),
+    /// the model can't be used right now (503)
+    Unavailable(String),
+    /// the http version is disabled by the tls policy (505)
+    VersionNotSupported(String),
+}
+
+/// Json error body
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ErrorResponse {
+    #[serde(rename = "code")]
+    pub code: u16,
+    #[serde(rename = "message")]
+    pub message: String,
+    #[serde(rename = "request_id")]
+    pub request_id: String,
+    #[serde(rename = "errors", skip_serializing_if = "Vec::is_empty", default)]
+    pub errors: Vec<FieldError>,
+}
+
+impl ServiceError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::BadRequest(..) => StatusCode::BAD_REQUEST,
+            ServiceError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            ServiceError::Conflict(_) => StatusCode::CONFLICT,
+            ServiceError::RequestTimeout(_) => StatusCode::REQUEST_TIMEOUT,
+            ServiceError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServiceError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServiceError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::VersionNotSupported(_) => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        }
+    }
+
+    pub fn into_response(self, request_id: &str) -> Response<Full<Bytes>> {
+        let status = self.status();
+        let message = self.to_string();
+        let allow = match &self {
+            ServiceError::MethodNotAllowed(_, allow) => Some(*allow),
+            _ => None,
+        };
+        let unauthorized = matches!(self, ServiceError::Unauthorized(_));
+        let retry_after = match &self {
+            ServiceError::TooManyRequests(retry_after) => Some(*retry_after),
+            _ => None,
+        };
+        let errors = match self {
+            ServiceError::BadRequest(_, errors) => errors,
+            _ => Vec::new(),
+        };
+        let body = ErrorResponse {
+            code: status.as_u16(),
+            message,
+            request_id: request_id.to_string(),
+            errors,
+        };
+        // serializing plain strings and numbers can't fail
+        let json = serde_json::to_string(&body).unwrap_or_default();
+        let mut response = Response::new(Full::from(json));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
+        if let Some(allow) = allow {
+            response
+                .headers_mut()
+                .insert(http::header::ALLOW, http::HeaderValue::from_static(allow));
+        }
+        if unauthorized {
+            response.headers_mut().insert(
+                http::header::WWW_AUTHENTICATE,
+                http::HeaderValue::from_static("Bearer"),
+            );
+        }
+        if let Some(retry_after) = retry_after {
+            response.headers_mut().insert(
+                http::header::RETRY_AFTER,
+                http::HeaderValue::from(retry_after),
+            );
+        }
+        response
+    }
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::BadRequest(message, _) => write!(f, "{}", message),
+            ServiceError::Unauthorized(message) => write!(f, "unauthorized: {}", message),
+            ServiceError::Forbidden(message) => write!(f, "forbidden: {}", message),
+            ServiceError::NotFound(what) => write!(f, "{} not found", what),
+            ServiceError::MethodNotAllowed(method, allow) => {
+                write!(f, "method {} not allowed (use {})", method, allow)
+            }
+            ServiceError::Conflict(message) => write!(f, "conflict: {}", message),
+            ServiceError::RequestTimeout(timeout) => {
+                write!(f, "request body not received within {}s", timeout)
+            }
+            ServiceError::PayloadTooLarge(limit) => {
+                write!(f, "request body is larger than {} bytes", limit)
+            }
+            ServiceError::TooManyRequests(retry_after) => {
+                write!(f, "rate limit exceeded, retry after {}s", retry_after)
+            }
+            ServiceError::Internal(message) => write!(f, "internal error: {}", message),
+            ServiceError::Unavailable(message) => write!(f, "service unavailable: {}", message),
+            ServiceError::VersionNotSupported(version) => {
+                write!(f, "{} is not supported", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
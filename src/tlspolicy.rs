@@ -0,0 +1,91 @@
+use crate::serverconfig::TlsPolicy;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache};
+use rustls::{ServerConfig, SupportedProtocolVersion};
+use std::error::Error;
+use std::sync::Arc;
+
+// alpn ids the server can speak
+const ALPN_PROTOCOLS: &[&str] = &["h2", "http/1.1", "http/1.0"];
+
+/// The ring provider restricted to the cipher suites in the policy
+pub fn provider(policy: &TlsPolicy) -> Result<Arc<CryptoProvider>, Box<dyn Error + Send + Sync>> {
+    let mut provider = ring::default_provider();
+    if !policy.cipher_suites.is_empty() {
+        let available: Vec<String> = provider
+            .cipher_suites
+            .iter()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .collect();
+        for name in policy.cipher_suites.iter() {
+            if !available.contains(name) {
+                return Err(Box::from(format!(
+                    "unknown cipher suite {} (available: {})",
+                    name,
+                    available.join(", ")
+                )));
+            }
+        }
+        provider.cipher_suites.retain(|suite| {
+            policy
+                .cipher_suites
+                .contains(&format!("{:?}", suite.suite()))
+        });
+    }
+    Ok(Arc::new(provider))
+}
+
+/// Server config with the protocol versions, alpn list and session resumption from the policy,
+/// fails when the policy can't be satisfied (i.e no cipher suite for the minimum version)
+pub fn server_config(
+    policy: &TlsPolicy,
+    provider: Arc<CryptoProvider>,
+    client_verifier: Arc<dyn ClientCertVerifier>,
+    resolver: Arc<dyn ResolvesServerCert>,
+) -> Result<ServerConfig, Box<dyn Error + Send + Sync>> {
+    let versions: &[&'static SupportedProtocolVersion] = match policy.min_version.as_str() {
+        "1.2" => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        "1.3" => &[&rustls::version::TLS13],
+        other => {
+            return Err(Box::from(format!(
+                "tls min_version must be 1.2 or 1.3, found {}",
+                other
+            )))
+        }
+    };
+    if policy.alpn.is_empty() {
+        return Err(Box::from("tls alpn list can't be empty"));
+    }
+    for protocol in policy.alpn.iter() {
+        if !ALPN_PROTOCOLS.contains(&protocol.as_str()) {
+            return Err(Box::from(format!(
+                "unknown alpn protocol {} (available: {})",
+                protocol,
+                ALPN_PROTOCOLS.join(", ")
+            )));
+        }
+    }
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .map_err(|e| format!("tls policy: {}", e))?
+        .with_client_cert_verifier(client_verifier)
+        .with_cert_resolver(resolver);
+    server_config.alpn_protocols = policy
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    server_config.session_storage = match policy.session_cache_size {
+        0 => Arc::new(NoServerSessionStorage {}),
+        size => ServerSessionMemoryCache::new(size),
+    };
+    if policy.session_tickets {
+        server_config.ticketer = ring::Ticketer::new()?;
+    } else if policy.session_cache_size == 0 {
+        // no way to resume, don't send tls 1.3 tickets
+        server_config.send_tls13_tickets = 0;
+    }
+    Ok(server_config)
+}
@@ -1,5 +1,8 @@
-use crate::dataset::{QueueMetricsBatcher, QueueMetricsDataset, NUM_CLASSES, NUM_FEATURES};
-use crate::model::ModelConfig;
+use crate::dataset::{QueueMetrics, QueueMetricsBatcher, QueueMetricsDataset};
+use crate::drift::TrainingStats;
+use crate::model::{Model, ModelConfig};
+use crate::schema::FeatureSchema;
+use burn::module::AutodiffModule;
 use burn::optim::AdamConfig;
 use burn::{
     data::{dataloader::DataLoaderBuilder, dataset::Dataset},
@@ -11,9 +14,12 @@ use burn::{
         metric::CudaMetric, metric::LossMetric, LearnerBuilder,
     },
 };
+use serde_derive::{Deserialize, Serialize};
 
 #[derive(Config)]
 pub struct ExpConfig {
+    pub model: ModelConfig,
+
     #[config(default = 20)]
     pub num_epochs: usize,
 
@@ -32,19 +38,40 @@ pub struct ExpConfig {
     pub learning_rate: f64,
 }
 
-fn create_artifact_dir(artifact_dir: &str) {
+/// Validation loss and accuracy of a trained model, saved as metrics.json in the artifact dir
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ValidMetrics {
+    pub loss: f64,
+    pub accuracy: f64,
+}
+
+pub fn create_artifact_dir(artifact_dir: &str) {
     // Remove existing artifacts before to get an accurate learner summary
     std::fs::remove_dir_all(artifact_dir).ok();
     std::fs::create_dir_all(artifact_dir).ok();
 }
 
-pub fn run<B: AutodiffBackend>(artifact_dir: &str, device: B::Device) {
+pub fn run<B: AutodiffBackend>(artifact_dir: &str, schema: &FeatureSchema, device: B::Device) {
     create_artifact_dir(artifact_dir);
 
     // config
+    // parameters are features,classes,hidden size
     let optimizer = AdamConfig::new();
-    let config = ExpConfig::new(optimizer);
-    let model = ModelConfig::new(NUM_FEATURES, NUM_CLASSES, 256).init(&device);
+    let model = ModelConfig::new(schema.input_size(), schema.num_classes, 256)
+        .with_embeddings(schema.embeddings());
+    let config = ExpConfig::new(model, optimizer);
+    train::<B>(artifact_dir, &config, schema, device);
+}
+
+/// Trains a model with the given config and saves the config, schema, model and validation metrics
+/// to the artifact dir
+pub fn train<B: AutodiffBackend>(
+    artifact_dir: &str,
+    config: &ExpConfig,
+    schema: &FeatureSchema,
+    device: B::Device,
+) -> ValidMetrics {
+    let model = config.model.init::<B>(&device);
     B::seed(config.seed);
 
     // save config
@@ -53,14 +80,25 @@ pub fn run<B: AutodiffBackend>(artifact_dir: &str, device: B::Device) {
         .expect("should save config");
 
     // define train/valid datasets and dataloaders
-    let train_dataset = QueueMetricsDataset::train();
-    let valid_dataset = QueueMetricsDataset::validation();
+    let train_dataset = QueueMetricsDataset::train(schema);
+    let valid_dataset = QueueMetricsDataset::validation(schema);
+
+    // save schema with the training medians (used to impute missing values when serving)
+    let items: Vec<QueueMetrics> = train_dataset.iter().collect();
+    let schema = &schema.clone().with_medians(&items);
+    schema
+        .save(&format!("{artifact_dir}/schema.json"))
+        .expect("should save schema");
+    // save the feature distributions, compared with the requests when serving (drift detection)
+    TrainingStats::from_items(schema, &items)
+        .save(&format!("{artifact_dir}/training-stats.json"))
+        .expect("should save training stats");
 
     println!("Train Dataset Size: {}", train_dataset.len());
     println!("Valid Dataset Size: {}", valid_dataset.len());
 
-    let batcher_train = QueueMetricsBatcher::<B>::new(device.clone());
-    let batcher_validate = QueueMetricsBatcher::<B::InnerBackend>::new(device.clone());
+    let batcher_train = QueueMetricsBatcher::<B>::new(device.clone(), schema);
+    let batcher_validate = QueueMetricsBatcher::<B::InnerBackend>::new(device.clone(), schema);
 
     let dataloader_train = DataLoaderBuilder::new(batcher_train)
         .batch_size(config.batch_size)
@@ -98,7 +136,57 @@ pub fn run<B: AutodiffBackend>(artifact_dir: &str, device: B::Device) {
 
     config.save(format!("{artifact_dir}/config.json")).unwrap();
 
+    let metrics = evaluate(&model_trained.valid(), schema, config.batch_size, device);
+    std::fs::write(
+        format!("{artifact_dir}/metrics.json"),
+        serde_json::to_string_pretty(&metrics).unwrap(),
+    )
+    .expect("should save metrics");
+
     model_trained
         .save_file(format!("{artifact_dir}/model"), &CompactRecorder::new())
         .expect("Failed to save trained model");
+
+    metrics
+}
+
+/// Computes the loss and accuracy of a model over the validation split
+pub fn evaluate<B: Backend>(
+    model: &Model<B>,
+    schema: &FeatureSchema,
+    batch_size: usize,
+    device: B::Device,
+) -> ValidMetrics {
+    let batcher = QueueMetricsBatcher::<B>::new(device.clone(), schema);
+    let dataloader = DataLoaderBuilder::new(batcher)
+        .batch_size(batch_size)
+        .build(QueueMetricsDataset::validation(schema));
+
+    let mut loss = 0.0;
+    let mut correct = 0;
+    let mut total = 0;
+    for batch in dataloader.iter() {
+        let output = model.forward_classification(batch);
+        let count = output.targets.dims()[0];
+        loss += output.loss.into_scalar().elem::<f64>() * count as f64;
+        let predicted = output.output.argmax(1).flatten::<1>(0, 1);
+        correct += predicted
+            .equal(output.targets)
+            .int()
+            .sum()
+            .into_scalar()
+            .elem::<i64>() as usize;
+        total += count;
+    }
+
+    if total == 0 {
+        return ValidMetrics {
+            loss: f64::INFINITY,
+            accuracy: 0.0,
+        };
+    }
+    ValidMetrics {
+        loss: loss / total as f64,
+        accuracy: correct as f64 / total as f64 * 100.0,
+    }
 }
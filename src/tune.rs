@@ -0,0 +1,573 @@
+use crate::model::ModelConfig;
+use crate::schema::FeatureSchema;
+use crate::training::{self, ExpConfig, ValidMetrics};
+use burn::optim::AdamConfig;
+use burn::tensor::backend::AutodiffBackend;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+
+/// a search dimension is either a list of candidate values or a continuous range
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum SearchDim {
+    Values(Vec<f64>),
+    Range {
+        min: f64,
+        max: f64,
+        #[serde(default)]
+        log: bool,
+    },
+}
+
+/// successive halving budget, trials start at min_epochs and the best 1/eta move up a rung
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Halving {
+    pub min_epochs: usize,
+    pub eta: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SearchSpace {
+    /// "grid" or "random"
+    pub strategy: String,
+    /// number of trials sampled in random mode
+    pub trials: Option<usize>,
+    pub seed: Option<u64>,
+    pub num_epochs: Option<usize>,
+    pub halving: Option<Halving>,
+    pub hidden_size: SearchDim,
+    pub dropout: SearchDim,
+    pub learning_rate: SearchDim,
+    pub batch_size: SearchDim,
+}
+
+#[derive(Clone, Debug)]
+struct Trial {
+    id: usize,
+    hidden_size: usize,
+    dropout: f64,
+    learning_rate: f64,
+    batch_size: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct LeaderboardEntry {
+    trial: usize,
+    rung: usize,
+    epochs: usize,
+    hidden_size: usize,
+    dropout: f64,
+    learning_rate: f64,
+    batch_size: usize,
+    valid_loss: f64,
+    valid_accuracy: f64,
+}
+
+pub fn read_search_space(name: &str) -> Result<SearchSpace, Box<dyn std::error::Error>> {
+    let json_data = File::open(name);
+    if json_data.is_err() {
+        return Err(Box::from(format!("search space file {} not found", name)));
+    }
+    let space = serde_json::from_reader(json_data.unwrap());
+    if space.is_err() {
+        return Err(Box::from(format!(
+            "parsing search space file {}",
+            space.err().unwrap()
+        )));
+    }
+    let space: SearchSpace = space.unwrap();
+    space.validate()?;
+    Ok(space)
+}
+
+impl SearchSpace {
+    /// rejects empty value lists, ranges that can't be sampled and values the model can't be built
+    /// with (sizes must be positive integers, dropout within [0, 1]), naming the parameter
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for (name, dim) in [
+            ("hidden_size", &self.hidden_size),
+            ("dropout", &self.dropout),
+            ("learning_rate", &self.learning_rate),
+            ("batch_size", &self.batch_size),
+        ] {
+            let check = |value: f64| -> Result<(), String> {
+                match name {
+                    "hidden_size" | "batch_size" if value < 1.0 || value.fract() != 0.0 => Err(
+                        format!("search space {name} value {value} should be a positive integer"),
+                    ),
+                    "dropout" if !(0.0..=1.0).contains(&value) => Err(format!(
+                        "search space {name} value {value} should be within [0, 1]"
+                    )),
+                    "learning_rate" if value <= 0.0 => Err(format!(
+                        "search space {name} value {value} should be greater than 0"
+                    )),
+                    _ if !value.is_finite() => Err(format!(
+                        "search space {name} value {value} should be a number"
+                    )),
+                    _ => Ok(()),
+                }
+            };
+            match dim {
+                SearchDim::Values(v) => {
+                    if v.is_empty() {
+                        return Err(Box::from(format!("search space {name} has no values")));
+                    }
+                    for value in v.iter() {
+                        check(*value)?;
+                    }
+                }
+                SearchDim::Range { min, max, log } => {
+                    if !min.is_finite() || !max.is_finite() || min >= max {
+                        return Err(Box::from(format!(
+                            "search space {name} range min {min} should be less than max {max}"
+                        )));
+                    }
+                    if *log && *min <= 0.0 {
+                        return Err(Box::from(format!(
+                            "search space {name} log range min {min} should be greater than 0"
+                        )));
+                    }
+                    // sizes are rounded once sampled, the bounds only need to round to at least 1
+                    match name {
+                        "hidden_size" | "batch_size" if min.round() < 1.0 => {
+                            return Err(Box::from(format!(
+                                "search space {name} range min {min} should be at least 1"
+                            )))
+                        }
+                        "hidden_size" | "batch_size" => {}
+                        _ => {
+                            check(*min)?;
+                            check(*max)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn run<B: AutodiffBackend>(
+    artifact_dir: &str,
+    search_space: &str,
+    schema: &FeatureSchema,
+    device: B::Device,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let space = read_search_space(search_space)?;
+    let num_epochs = space.num_epochs.unwrap_or(20);
+    let trials = match space.strategy.as_str() {
+        "grid" => grid(&space)?,
+        "random" => random(&space),
+        &_ => {
+            return Err(Box::from(format!(
+                "strategy {} not available (use grid or random)",
+                space.strategy
+            )))
+        }
+    };
+    if trials.is_empty() {
+        return Err(Box::from("search space produced no trials"));
+    }
+
+    let tune_dir = format!("{artifact_dir}/tune");
+    training::create_artifact_dir(&tune_dir);
+
+    let (best, mut leaderboard) = successive_halving(
+        &space,
+        trials,
+        num_epochs,
+        |rung, trial, epochs| {
+            println!(
+                "tune rung {} trial {:0>3} : epochs {} hidden_size {} dropout {} learning_rate {} batch_size {}",
+                rung,
+                trial.id,
+                epochs,
+                trial.hidden_size,
+                trial.dropout,
+                trial.learning_rate,
+                trial.batch_size
+            );
+            let metrics = run_trial::<B>(&tune_dir, trial, epochs, &space, schema, device.clone());
+            println!(
+                "tune rung {} trial {:0>3} : valid loss {:.4} accuracy {:.2}%",
+                rung, trial.id, metrics.loss, metrics.accuracy
+            );
+            metrics
+        },
+    );
+
+    leaderboard.sort_by(|a, b| {
+        b.rung
+            .cmp(&a.rung)
+            .then(a.valid_loss.total_cmp(&b.valid_loss))
+    });
+    let mut wtr = csv::Writer::from_path(format!("{tune_dir}/leaderboard.csv"))?;
+    for entry in leaderboard.iter() {
+        wtr.serialize(entry)?;
+    }
+    wtr.flush()?;
+
+    // keep the best model as the served model
+    let trial_dir = format!("{tune_dir}/trial-{:0>3}", best.trial);
+    std::fs::copy(
+        format!("{trial_dir}/model.mpk"),
+        format!("{artifact_dir}/model.mpk"),
+    )?;
+    std::fs::copy(
+        format!("{trial_dir}/config.json"),
+        format!("{artifact_dir}/config.json"),
+    )?;
+    std::fs::copy(
+        format!("{trial_dir}/schema.json"),
+        format!("{artifact_dir}/schema.json"),
+    )?;
+    std::fs::copy(
+        format!("{trial_dir}/metrics.json"),
+        format!("{artifact_dir}/metrics.json"),
+    )?;
+    std::fs::copy(
+        format!("{trial_dir}/training-stats.json"),
+        format!("{artifact_dir}/training-stats.json"),
+    )?;
+    println!(
+        "best trial {:0>3} : hidden_size {} dropout {} learning_rate {} batch_size {} epochs {} : valid loss {:.4} accuracy {:.2}%",
+        best.trial,
+        best.hidden_size,
+        best.dropout,
+        best.learning_rate,
+        best.batch_size,
+        best.epochs,
+        best.valid_loss,
+        best.valid_accuracy
+    );
+    println!("leaderboard written to {tune_dir}/leaderboard.csv");
+    Ok(())
+}
+
+// runs every trial of a rung through train, the best 1/eta move up to the next rung with eta times
+// the epochs until num_epochs (a single rung without halving). Returns the best trial of the last
+// rung and every result
+fn successive_halving(
+    space: &SearchSpace,
+    trials: Vec<Trial>,
+    num_epochs: usize,
+    mut train: impl FnMut(usize, &Trial, usize) -> ValidMetrics,
+) -> (LeaderboardEntry, Vec<LeaderboardEntry>) {
+    let mut rung_epochs = match &space.halving {
+        Some(halving) => halving.min_epochs.clamp(1, num_epochs),
+        None => num_epochs,
+    };
+    let mut survivors = trials;
+    let mut leaderboard: Vec<LeaderboardEntry> = Vec::new();
+    let mut rung = 0;
+    let best = loop {
+        let mut results: Vec<LeaderboardEntry> = Vec::new();
+        for trial in survivors.iter() {
+            let metrics = train(rung, trial, rung_epochs);
+            results.push(LeaderboardEntry {
+                trial: trial.id,
+                rung,
+                epochs: rung_epochs,
+                hidden_size: trial.hidden_size,
+                dropout: trial.dropout,
+                learning_rate: trial.learning_rate,
+                batch_size: trial.batch_size,
+                valid_loss: metrics.loss,
+                valid_accuracy: metrics.accuracy,
+            });
+        }
+        results.sort_by(|a, b| a.valid_loss.total_cmp(&b.valid_loss));
+        leaderboard.extend(results.clone());
+
+        let halving = match &space.halving {
+            Some(halving) if results.len() > 1 && rung_epochs < num_epochs => halving,
+            _ => break results[0].clone(),
+        };
+        let eta = halving.eta.max(2);
+        let keep = results.len().div_ceil(eta);
+        let kept: Vec<usize> = results.iter().take(keep).map(|r| r.trial).collect();
+        survivors.retain(|t| kept.contains(&t.id));
+        rung_epochs = (rung_epochs * eta).min(num_epochs);
+        rung += 1;
+    };
+    (best, leaderboard)
+}
+
+// trials are retrained from scratch for each rung, with the rung's epoch budget
+fn run_trial<B: AutodiffBackend>(
+    tune_dir: &str,
+    trial: &Trial,
+    num_epochs: usize,
+    space: &SearchSpace,
+    schema: &FeatureSchema,
+    device: B::Device,
+) -> ValidMetrics {
+    let trial_dir = format!("{tune_dir}/trial-{:0>3}", trial.id);
+    training::create_artifact_dir(&trial_dir);
+    let model = ModelConfig::new(schema.input_size(), schema.num_classes, trial.hidden_size)
+        .with_dropout(trial.dropout)
+        .with_embeddings(schema.embeddings());
+    let config = ExpConfig::new(model, AdamConfig::new())
+        .with_num_epochs(num_epochs)
+        .with_batch_size(trial.batch_size)
+        .with_learning_rate(trial.learning_rate)
+        .with_seed(space.seed.unwrap_or(1337));
+    training::train::<B>(&trial_dir, &config, schema, device)
+}
+
+fn grid(space: &SearchSpace) -> Result<Vec<Trial>, Box<dyn std::error::Error>> {
+    let values = |name: &str, dim: &SearchDim| match dim {
+        SearchDim::Values(v) => Ok(v.clone()),
+        SearchDim::Range { .. } => Err(format!("grid search needs a list of values for {name}")),
+    };
+    let hidden_sizes = values("hidden_size", &space.hidden_size)?;
+    let dropouts = values("dropout", &space.dropout)?;
+    let learning_rates = values("learning_rate", &space.learning_rate)?;
+    let batch_sizes = values("batch_size", &space.batch_size)?;
+
+    let mut trials = Vec::new();
+    for hidden_size in hidden_sizes.iter() {
+        for dropout in dropouts.iter() {
+            for learning_rate in learning_rates.iter() {
+                for batch_size in batch_sizes.iter() {
+                    trials.push(Trial {
+                        id: trials.len(),
+                        hidden_size: *hidden_size as usize,
+                        dropout: *dropout,
+                        learning_rate: *learning_rate,
+                        batch_size: *batch_size as usize,
+                    });
+                }
+            }
+        }
+    }
+    Ok(trials)
+}
+
+fn random(space: &SearchSpace) -> Vec<Trial> {
+    let mut rng = XorShift::new(space.seed.unwrap_or(1337));
+    (0..space.trials.unwrap_or(10))
+        .map(|id| Trial {
+            id,
+            hidden_size: (rng.sample(&space.hidden_size).round() as usize).max(1),
+            dropout: rng.sample(&space.dropout),
+            learning_rate: rng.sample(&space.learning_rate),
+            batch_size: (rng.sample(&space.batch_size).round() as usize).max(1),
+        })
+        .collect()
+}
+
+// small deterministic generator, so a search can be repeated from its seed
+struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn sample(&mut self, dim: &SearchDim) -> f64 {
+        match dim {
+            SearchDim::Values(v) => {
+                let index = (self.next_f64() * v.len() as f64) as usize;
+                v[index.min(v.len() - 1)]
+            }
+            SearchDim::Range { min, max, log } => {
+                let r = self.next_f64();
+                if *log {
+                    (min.ln() + r * (max.ln() - min.ln())).exp()
+                } else {
+                    min + r * (max - min)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn space(strategy: &str) -> SearchSpace {
+        serde_json::from_value(json!({
+            "strategy": strategy,
+            "trials": 8,
+            "seed": 7,
+            "hidden_size": [32, 64],
+            "dropout": [0.0, 0.5],
+            "learning_rate": {"min": 0.0001, "max": 0.1, "log": true},
+            "batch_size": [16, 32, 64]
+        }))
+        .unwrap()
+    }
+
+    fn rejected(space: &SearchSpace, message: &str) {
+        let err = space.validate().unwrap_err().to_string();
+        assert!(err.contains(message), "{err}");
+    }
+
+    #[test]
+    fn parses_values_and_ranges() {
+        let space = space("random");
+        assert!(space.validate().is_ok());
+        assert!(matches!(&space.hidden_size, SearchDim::Values(v) if v == &vec![32.0, 64.0]));
+        assert!(matches!(
+            space.learning_rate,
+            SearchDim::Range { log: true, .. }
+        ));
+        let range: SearchDim = serde_json::from_value(json!({"min": 0.1, "max": 0.5})).unwrap();
+        assert!(matches!(range, SearchDim::Range { log: false, .. }));
+    }
+
+    #[test]
+    fn rejects_dropout_outside_unit_interval() {
+        let mut space = space("grid");
+        space.dropout = SearchDim::Values(vec![0.2, 1.5]);
+        rejected(&space, "dropout value 1.5 should be within [0, 1]");
+        space.dropout = SearchDim::Range {
+            min: -0.1,
+            max: 0.5,
+            log: false,
+        };
+        rejected(&space, "dropout value -0.1 should be within [0, 1]");
+    }
+
+    #[test]
+    fn rejects_sizes_that_are_not_positive_integers() {
+        let mut space = space("grid");
+        space.hidden_size = SearchDim::Values(vec![32.0, 0.0]);
+        rejected(&space, "hidden_size value 0 should be a positive integer");
+        space.hidden_size = SearchDim::Values(vec![32.5]);
+        rejected(
+            &space,
+            "hidden_size value 32.5 should be a positive integer",
+        );
+        space.hidden_size = SearchDim::Values(vec![32.0]);
+        space.batch_size = SearchDim::Range {
+            min: 0.2,
+            max: 64.0,
+            log: false,
+        };
+        rejected(&space, "batch_size range min 0.2 should be at least 1");
+    }
+
+    #[test]
+    fn rejects_empty_and_inverted_dimensions() {
+        let mut space = space("grid");
+        space.batch_size = SearchDim::Values(vec![]);
+        rejected(&space, "batch_size has no values");
+        space.batch_size = SearchDim::Values(vec![16.0]);
+        space.learning_rate = SearchDim::Range {
+            min: 0.1,
+            max: 0.01,
+            log: false,
+        };
+        rejected(
+            &space,
+            "learning_rate range min 0.1 should be less than max 0.01",
+        );
+        space.learning_rate = SearchDim::Range {
+            min: 0.0,
+            max: 0.1,
+            log: true,
+        };
+        rejected(
+            &space,
+            "learning_rate log range min 0 should be greater than 0",
+        );
+    }
+
+    #[test]
+    fn grid_expands_every_combination() {
+        let mut space = space("grid");
+        assert!(grid(&space).is_err());
+        space.learning_rate = SearchDim::Values(vec![0.001]);
+        let trials = grid(&space).unwrap();
+        assert_eq!(trials.len(), 2 * 2 * 3);
+        assert_eq!(
+            trials.iter().map(|t| t.id).collect::<Vec<_>>(),
+            (0..12).collect::<Vec<_>>()
+        );
+        let last = trials.last().unwrap();
+        assert_eq!((last.hidden_size, last.batch_size), (64, 64));
+        assert_eq!(last.dropout, 0.5);
+    }
+
+    #[test]
+    fn random_samples_within_the_space_and_repeats_from_seed() {
+        let space = space("random");
+        let trials = random(&space);
+        assert_eq!(trials.len(), 8);
+        for trial in trials.iter() {
+            assert!([32, 64].contains(&trial.hidden_size));
+            assert!([16, 32, 64].contains(&trial.batch_size));
+            assert!([0.0, 0.5].contains(&trial.dropout));
+            assert!((0.0001..=0.1).contains(&trial.learning_rate));
+        }
+        let again = random(&space);
+        assert!(trials
+            .iter()
+            .zip(again.iter())
+            .all(|(a, b)| a.learning_rate == b.learning_rate && a.hidden_size == b.hidden_size));
+    }
+
+    #[test]
+    fn halving_keeps_the_best_trials_for_each_rung() {
+        let mut space = space("random");
+        space.halving = Some(Halving {
+            min_epochs: 2,
+            eta: 3,
+        });
+        let trials = random(&space);
+        let mut runs = Vec::new();
+        // lower ids get the lower loss, so trials 0..3 survive the first rung
+        let (best, leaderboard) = successive_halving(&space, trials, 10, |rung, trial, epochs| {
+            runs.push((rung, trial.id, epochs));
+            ValidMetrics {
+                loss: trial.id as f64 + 1.0 / epochs as f64,
+                accuracy: 0.0,
+            }
+        });
+        let rungs: Vec<(usize, usize)> = (0..3)
+            .map(|rung| {
+                let entries: Vec<&(usize, usize, usize)> =
+                    runs.iter().filter(|r| r.0 == rung).collect();
+                (entries.len(), entries[0].2)
+            })
+            .collect();
+        // 8 trials at 2 epochs, ceil(8/3) = 3 at 6, ceil(3/3) = 1 at 10
+        assert_eq!(rungs, vec![(8, 2), (3, 6), (1, 10)]);
+        assert_eq!(leaderboard.len(), 12);
+        assert_eq!((best.trial, best.rung, best.epochs), (0, 2, 10));
+        assert!(runs
+            .iter()
+            .filter(|r| r.0 == 1)
+            .all(|r| [0, 1, 2].contains(&r.1)));
+    }
+
+    #[test]
+    fn without_halving_every_trial_runs_once() {
+        let space = space("random");
+        let trials = random(&space);
+        let mut runs = 0;
+        let (best, leaderboard) = successive_halving(&space, trials, 5, |_, trial, epochs| {
+            runs += 1;
+            assert_eq!(epochs, 5);
+            ValidMetrics {
+                loss: (8 - trial.id) as f64,
+                accuracy: 0.0,
+            }
+        });
+        assert_eq!((runs, leaderboard.len()), (8, 8));
+        assert_eq!(best.trial, 7);
+    }
+}